@@ -0,0 +1,212 @@
+use std::{collections::HashSet, fmt};
+
+use crate::{
+    html,
+    links::{self, Link, LinkSource},
+};
+
+/// The minimum share of the words of a paragraph
+/// found in the other part for it to count as present.
+const MIN_OVERLAP: f64 = 0.5;
+
+/// A difference between the text part of an email and its html part,
+/// see [`crate::Email::check_text_alternative`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TextMismatch {
+    /// A paragraph of the html part is missing from the text part.
+    MissingParagraph(String),
+    /// A paragraph of the text part is missing from the html part.
+    ExtraParagraph(String),
+    /// The URL of a link in the html part is missing from the text part.
+    MissingLink(String),
+}
+
+impl fmt::Display for TextMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingParagraph(paragraph) => {
+                write!(f, "paragraph missing from the text part: {paragraph}")
+            }
+            Self::ExtraParagraph(paragraph) => {
+                write!(f, "paragraph missing from the html part: {paragraph}")
+            }
+            Self::MissingLink(url) => {
+                write!(f, "link missing from the text part: {url}")
+            }
+        }
+    }
+}
+
+/// Compare the text part with the html part.
+///
+/// Paragraphs are compared by their pairs of consecutive words,
+/// ignoring case, punctuation and URLs,
+/// so that small differences in wording or line breaks are accepted.
+pub(crate) fn check(body_text: &str, body_html: &str) -> Vec<TextMismatch> {
+    if body_text.trim().is_empty() || body_html.trim().is_empty() {
+        return Vec::new();
+    }
+    let rendered = html::render_text(body_html, false);
+    let html_paragraphs = rendered
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+    let without_urls = links::text_url_regex().replace_all(body_text, " ");
+    let text_paragraphs = paragraphs(&without_urls);
+
+    let html_words = Words::new(html_paragraphs.iter().copied());
+    let text_words = Words::new(text_paragraphs.iter().map(|p| p.as_str()));
+    let mut mismatches = html_paragraphs
+        .iter()
+        .filter(|paragraph| !text_words.contains(paragraph))
+        .map(|paragraph| TextMismatch::MissingParagraph(paragraph.to_string()))
+        .collect::<Vec<_>>();
+    mismatches.extend(
+        text_paragraphs
+            .iter()
+            .filter(|paragraph| !html_words.contains(paragraph))
+            .map(|paragraph| TextMismatch::ExtraParagraph(paragraph.clone())),
+    );
+
+    let links = links::extract(body_html, body_text);
+    let text_urls = links
+        .iter()
+        .filter(|link| link.source == LinkSource::Text)
+        .flat_map(|link| [link.url.clone(), link.target()])
+        .collect::<HashSet<_>>();
+    let mut missing = HashSet::new();
+    mismatches.extend(
+        links
+            .iter()
+            .filter(|link| link.source == LinkSource::Html && is_web(link))
+            .filter(|link| {
+                !text_urls.contains(&link.url)
+                    && !text_urls.contains(&link.target())
+            })
+            .filter(|link| missing.insert(link.url.clone()))
+            .map(|link| TextMismatch::MissingLink(link.url.clone())),
+    );
+    mismatches
+}
+
+/// Split text into paragraphs separated by blank lines,
+/// with whitespace collapsed.
+fn paragraphs(text: &str) -> Vec<String> {
+    let mut paragraphs = Vec::new();
+    let mut words = Vec::new();
+    // NOTE: `lines` also splits on "\r\n", as used in received emails
+    for line in text.lines().chain([""]) {
+        if line.trim().is_empty() {
+            if !words.is_empty() {
+                paragraphs.push(words.join(" "));
+                words.clear();
+            }
+        } else {
+            words.extend(line.split_whitespace());
+        }
+    }
+    paragraphs
+}
+
+fn is_web(link: &Link) -> bool {
+    let url = link.url.to_ascii_lowercase();
+    url.starts_with("https://") || url.starts_with("http://")
+}
+
+/// The words and pairs of consecutive words of some paragraphs.
+struct Words {
+    words: HashSet<String>,
+    pairs: HashSet<(String, String)>,
+}
+
+impl Words {
+    fn new<'a>(paragraphs: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut words = HashSet::new();
+        let mut pairs = HashSet::new();
+        for paragraph in paragraphs {
+            let paragraph = normalize(paragraph);
+            words.extend(paragraph.iter().cloned());
+            pairs.extend(
+                paragraph
+                    .windows(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone())),
+            );
+        }
+        Self { words, pairs }
+    }
+
+    /// Whether most of the words of the paragraph are found,
+    /// or true if it has no words.
+    fn contains(&self, paragraph: &str) -> bool {
+        let words = normalize(paragraph);
+        let (found, total) = match words.as_slice() {
+            [] => return true,
+            [word] => (usize::from(self.words.contains(word)), 1),
+            _ => {
+                let found = words
+                    .windows(2)
+                    .filter(|pair| {
+                        self.pairs.contains(&(pair[0].clone(), pair[1].clone()))
+                    })
+                    .count();
+                (found, words.len() - 1)
+            }
+        };
+        found as f64 >= total as f64 * MIN_OVERLAP
+    }
+}
+
+/// The lowercase words of a paragraph, without punctuation.
+fn normalize(paragraph: &str) -> Vec<String> {
+    paragraph
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, TextMismatch};
+
+    #[test]
+    fn mismatches() {
+        let html = "<h1>Welcome!</h1>\
+            <p>Thanks for signing up. Please\n<a href=\"https://click.\
+            example.com/?u=https%3A%2F%2Fexample.com%2Fconfirm\">confirm\
+            </a> your address.</p>\
+            <p>Your trial ends on <b>May 1</b>.</p>\
+            <p><a href=\"#top\">Back to top</a></p>\
+            <p><a href=\"https://example.com/help\">Help</a></p>";
+        let text = "WELCOME!\n\n\
+            Thanks for signing up. Please confirm your\n\
+            address: https://example.com/confirm\n\n\
+            Your trial ends on May 1.\n\n\
+            Back to top\n\n\
+            Help: https://example.com/help";
+        assert_eq!(check(text, html), []);
+        assert_eq!(check("", html), []);
+
+        let stale = "Welcome!\r\n\r\n\
+            Thanks for signing up. Please confirm your address:\r\n\
+            https://example.com/confirm\r\n \r\n\
+            Your free trial lasts 14 days, enjoy!\r\n";
+        assert_eq!(
+            check(stale, html),
+            [
+                TextMismatch::MissingParagraph(
+                    "Your trial ends on May 1.".to_string()
+                ),
+                TextMismatch::MissingParagraph("Back to top".to_string()),
+                TextMismatch::MissingParagraph("Help".to_string()),
+                TextMismatch::ExtraParagraph(
+                    "Your free trial lasts 14 days, enjoy!".to_string()
+                ),
+                TextMismatch::MissingLink(
+                    "https://example.com/help".to_string()
+                ),
+            ]
+        );
+    }
+}
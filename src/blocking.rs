@@ -0,0 +1,253 @@
+//! A synchronous wrapper around the server,
+//! for use from non-async code.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::mpsc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::oneshot;
+
+use crate::{Auth, Email};
+
+/// An SMTP email server running on a background thread.
+///
+/// The server owns a tokio runtime on its own thread,
+/// so it can be used from plain `#[test]` functions,
+/// for example with lettre's blocking `SmtpTransport`.
+/// Any errors while receiving are discarded.
+///
+/// The server shuts down when dropped.
+pub struct Server {
+    address: SocketAddr,
+    emails: mpsc::Receiver<Email>,
+    /// Emails skipped by [`Server::receive_matching`].
+    skipped: RefCell<VecDeque<Email>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Start a new server instance.
+    pub fn start(
+        address: SocketAddr,
+        auth: Auth,
+    ) -> Result<Self, std::io::Error> {
+        Self::start_configured(address, auth, |_| {})
+    }
+
+    /// Start a new server instance,
+    /// calling the given function to configure the wrapped
+    /// [`crate::Server`] before it accepts any connections.
+    pub fn start_configured<F>(
+        address: SocketAddr,
+        auth: Auth,
+        configure: F,
+    ) -> Result<Self, std::io::Error>
+    where
+        F: FnOnce(&mut crate::Server) + Send + 'static,
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()?;
+        let (started_tx, started_rx) = mpsc::channel();
+        let (email_tx, email_rx) = mpsc::channel();
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+        let thread = std::thread::spawn(move || {
+            runtime.block_on(async move {
+                let mut server = match crate::Server::start(address, auth).await
+                {
+                    Ok(server) => server,
+                    Err(error) => {
+                        let _ = started_tx.send(Err(error));
+                        return;
+                    }
+                };
+                configure(&mut server);
+                let _ = started_tx.send(server.address());
+                loop {
+                    tokio::select! {
+                        email = server.receive() => {
+                            if email_tx.send(email).is_err() {
+                                return;
+                            }
+                        }
+                        _ = &mut shutdown_rx => return,
+                    }
+                }
+            })
+        });
+        let address = match started_rx.recv() {
+            Ok(result) => result?,
+            Err(_) => {
+                return Err(std::io::Error::other("server thread stopped"))
+            }
+        };
+        Ok(Self {
+            address,
+            emails: email_rx,
+            skipped: RefCell::default(),
+            shutdown: Some(shutdown_tx),
+            thread: Some(thread),
+        })
+    }
+
+    /// Return the address and port to which this server bound.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Receive a single email,
+    /// waiting at most for the given duration.
+    ///
+    /// Returns `None` if no email was received in time.
+    pub fn receive_timeout(&self, timeout: Duration) -> Option<Email> {
+        if let Some(email) = self.skipped.borrow_mut().pop_front() {
+            return Some(email);
+        }
+        self.emails.recv_timeout(timeout).ok()
+    }
+
+    /// Receive the next email matching the predicate,
+    /// waiting at most for the given duration.
+    ///
+    /// Returns `None` if no matching email was received in time.
+    /// Other emails are skipped, but are still returned
+    /// by later calls to [`Server::receive_timeout`]
+    /// and [`Server::emails`].
+    pub fn receive_matching<F>(
+        &self,
+        timeout: Duration,
+        mut predicate: F,
+    ) -> Option<Email>
+    where
+        F: FnMut(&Email) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let email = self.emails.recv_timeout(timeout).ok()?;
+            if predicate(&email) {
+                return Some(email);
+            }
+            self.skipped.borrow_mut().push_back(email);
+        }
+    }
+
+    /// Receive the next email for the given recipient,
+    /// waiting at most for the given duration,
+    /// see [`Server::receive_matching`].
+    pub fn receive_to(
+        &self,
+        address: &str,
+        timeout: Duration,
+    ) -> Option<Email> {
+        self.receive_matching(timeout, |email| {
+            email.recipient(address).is_some()
+        })
+    }
+
+    /// Take all emails received so far, without waiting.
+    pub fn emails(&self) -> Vec<Email> {
+        let mut emails = Vec::from(self.skipped.take());
+        emails.extend(self.emails.try_iter());
+        emails
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "lettre"))]
+mod tests {
+    use std::time::Duration;
+
+    use lettre::{message::Message, SmtpTransport, Transport};
+
+    use super::Server;
+    use crate::{Auth, MessageBuilderExt};
+
+    const TIMEOUT: Duration = Duration::from_millis(1000);
+
+    #[test]
+    fn send_and_receive() {
+        let server = Server::start_configured(
+            "127.0.0.1:0".parse().unwrap(),
+            Auth::AcceptAll,
+            |server| server.set_hostname(Some("mx.example.com".to_string())),
+        )
+        .unwrap();
+        let address = server.address();
+        let client = SmtpTransport::builder_dangerous(address.ip().to_string())
+            .port(address.port())
+            .build();
+        assert!(server.emails().is_empty());
+
+        for subject in ["First", "Second"] {
+            let message = Message::builder()
+                .from("Friend <friend@example.com>".parse().unwrap())
+                .to("MySelf <self@example.com>".parse().unwrap())
+                .subject(subject)
+                .body_text_and_html(
+                    "Welcome!".to_string(),
+                    "<p>Welcome!</p>".to_string(),
+                )
+                .unwrap();
+            client.send(&message).expect("error sending email");
+        }
+
+        for subject in ["First", "Second"] {
+            let email = server.receive_timeout(TIMEOUT).expect("timeout");
+            assert_eq!(&email.subject, subject);
+        }
+        assert!(server.emails().is_empty());
+        assert!(server.receive_timeout(Duration::from_millis(10)).is_none());
+    }
+
+    #[test]
+    fn receive_to() {
+        let server =
+            Server::start("127.0.0.1:0".parse().unwrap(), Auth::AcceptAll)
+                .unwrap();
+        let address = server.address();
+        let client = SmtpTransport::builder_dangerous(address.ip().to_string())
+            .port(address.port())
+            .build();
+
+        for recipient in ["other@example.com", "User@example.com"] {
+            let message = Message::builder()
+                .from("Friend <friend@example.com>".parse().unwrap())
+                .to(format!("Recipient <{recipient}>").parse().unwrap())
+                .subject(recipient)
+                .body_text_and_html(
+                    "Welcome!".to_string(),
+                    "<p>Welcome!</p>".to_string(),
+                )
+                .unwrap();
+            client.send(&message).expect("error sending email");
+        }
+
+        let email = server
+            .receive_to("user@example.com", TIMEOUT)
+            .expect("timeout");
+        assert_eq!(&email.subject, "User@example.com");
+        assert!(server
+            .receive_to("user@example.com", Duration::from_millis(10))
+            .is_none());
+        let skipped = server.emails();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(&skipped[0].subject, "other@example.com");
+    }
+}
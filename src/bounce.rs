@@ -0,0 +1,372 @@
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    envelope::{DsnNotify, DsnReturn, Recipient},
+    reply::{EnhancedCode, Reply},
+    smtp::{Data, Settings},
+};
+
+/// A delivery status notification (RFC 3464)
+/// generated by the server for rejected recipients.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Bounce {
+    /// The email address this bounce is sent to.
+    ///
+    /// This is the envelope sender of the original message,
+    /// unless a return path was configured on the server.
+    pub address_to: String,
+
+    /// The recipients that could not be delivered to.
+    pub recipients: Vec<FailedRecipient>,
+
+    /// The complete `multipart/report` message,
+    /// including headers.
+    pub message: Vec<u8>,
+}
+
+/// A recipient reported as failed in a [`Bounce`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct FailedRecipient {
+    /// The recipient as received in the original SMTP exchange.
+    pub recipient: Recipient,
+
+    /// The enhanced status code (RFC 3463), for example `5.1.1`.
+    pub status: EnhancedCode,
+
+    /// The SMTP reply that caused the failure.
+    pub reply: Reply,
+}
+
+/// Where the server delivers generated bounces.
+#[derive(Clone)]
+pub enum BounceDelivery {
+    /// Keep bounces in the server,
+    /// to be taken with [`Server::bounces`](crate::Server::bounces).
+    Mailbox,
+    /// Call the provided function with each bounce.
+    Callback(Arc<dyn Fn(Bounce) + Send + Sync>),
+}
+
+impl std::fmt::Debug for BounceDelivery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mailbox => f.write_str("Mailbox"),
+            Self::Callback(_) => f.write_str("Callback(..)"),
+        }
+    }
+}
+
+/// The status reported if the rejection has no enhanced status code.
+const DEFAULT_STATUS: EnhancedCode = EnhancedCode::new(5, 0, 0);
+
+/// Deliver a bounce for the rejected recipients of a received message,
+/// if bounces are enabled and any were rejected.
+///
+/// The message itself is not delivered to those recipients.
+pub(crate) fn deliver(data: &Data, settings: &Settings, reporting_mta: &str) {
+    let Some(delivery) = &settings.bounces else {
+        return;
+    };
+    let Some(bounce) = generate(data, settings, reporting_mta) else {
+        return;
+    };
+    match delivery {
+        BounceDelivery::Mailbox => {
+            // NOTE: the server keeps the receiver itself,
+            //       so this channel never closes
+            let _ = settings.bounce_channel.send(bounce);
+        }
+        BounceDelivery::Callback(callback) => callback(bounce),
+    }
+}
+
+/// Generate a bounce for the rejected recipients of a received message.
+///
+/// Returns `None` if no rejected recipient requested
+/// a failure notification, or if there is no address
+/// to send the bounce to.
+fn generate(
+    data: &Data,
+    settings: &Settings,
+    reporting_mta: &str,
+) -> Option<Bounce> {
+    let recipients = data
+        .bounced
+        .iter()
+        .filter(|recipient| match &recipient.notify {
+            Some(notify) => notify.contains(&DsnNotify::Failure),
+            None => true,
+        })
+        .filter_map(|recipient| {
+            let reply = settings
+                .rejection(&recipient.address)
+                .filter(Reply::is_permanent)?;
+            Some(FailedRecipient {
+                recipient: recipient.clone(),
+                status: reply.enhanced.unwrap_or(DEFAULT_STATUS),
+                reply,
+            })
+        })
+        .collect::<Vec<_>>();
+    if recipients.is_empty() {
+        return None;
+    }
+    let address_to = settings
+        .bounce_return_path
+        .clone()
+        .unwrap_or_else(|| data.address_from.clone());
+    // NOTE: messages with a null reverse-path must never be bounced
+    if address_to.is_empty() {
+        return None;
+    }
+    let message = format_message(
+        data,
+        &address_to,
+        &recipients,
+        reporting_mta,
+        SystemTime::now(),
+    );
+    Some(Bounce {
+        address_to,
+        recipients,
+        message,
+    })
+}
+
+fn format_message(
+    data: &Data,
+    address_to: &str,
+    recipients: &[FailedRecipient],
+    reporting_mta: &str,
+    time: SystemTime,
+) -> Vec<u8> {
+    let timestamp = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let boundary = format!(
+        "bounce-{}-{}",
+        timestamp.as_secs(),
+        timestamp.subsec_nanos()
+    );
+    let date = format_date(timestamp.as_secs());
+    // NOTE: the reporting MTA can be an IP address,
+    //       which is only valid as a domain in an address literal
+    let domain = match reporting_mta.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => format!("[{ip}]"),
+        Ok(IpAddr::V6(ip)) => format!("[IPv6:{ip}]"),
+        Err(_) => reporting_mta.to_string(),
+    };
+
+    let mut message = format!(
+        "From: Mail Delivery System <MAILER-DAEMON@{domain}>\r\n\
+         To: <{address_to}>\r\n\
+         Subject: Undelivered Mail Returned to Sender\r\n\
+         Date: {date}\r\n\
+         Message-ID: <{boundary}@{domain}>\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status;\r\n \
+         boundary=\"{boundary}\"\r\n\
+         \r\n"
+    );
+
+    message.push_str(&format!(
+        "--{boundary}\r\n\
+         Content-Type: text/plain; charset=us-ascii\r\n\
+         \r\n\
+         This is the mail system at host {reporting_mta}.\r\n\
+         \r\n\
+         Your message could not be delivered \
+         to one or more recipients.\r\n\
+         \r\n"
+    ));
+    for failed in recipients {
+        message.push_str(&format!(
+            "<{}>: {}",
+            failed.recipient.address, failed.reply
+        ));
+    }
+    message.push_str("\r\n");
+
+    message.push_str(&format!(
+        "--{boundary}\r\n\
+         Content-Type: message/delivery-status\r\n\
+         \r\n\
+         Reporting-MTA: dns; {reporting_mta}\r\n"
+    ));
+    if let Some(envelope_id) = &data.dsn_envelope_id {
+        message.push_str(&format!("Original-Envelope-Id: {envelope_id}\r\n"));
+    }
+    message.push_str(&format!("Arrival-Date: {date}\r\n"));
+    for failed in recipients {
+        message.push_str("\r\n");
+        if let Some(original) = &failed.recipient.original_recipient {
+            message.push_str(&format!("Original-Recipient: {original}\r\n"));
+        }
+        message.push_str(&format!(
+            "Final-Recipient: rfc822; {}\r\n\
+             Action: failed\r\n\
+             Status: {}\r\n\
+             Diagnostic-Code: smtp; {}\r\n",
+            failed.recipient.address,
+            failed.status,
+            diagnostic_code(&failed.reply),
+        ));
+    }
+    message.push_str("\r\n");
+
+    let mut message = message.into_bytes();
+    match data.dsn_return {
+        Some(DsnReturn::Headers) => {
+            message.extend(
+                format!(
+                    "--{boundary}\r\n\
+                     Content-Type: text/rfc822-headers\r\n\
+                     \r\n"
+                )
+                .bytes(),
+            );
+            let headers_end = data
+                .email
+                .windows(4)
+                .position(|window| window == b"\r\n\r\n")
+                .map(|position| position + 2)
+                .unwrap_or(data.email.len());
+            message.extend(&data.email[..headers_end]);
+        }
+        Some(DsnReturn::Full) | None => {
+            message.extend(
+                format!(
+                    "--{boundary}\r\n\
+                     Content-Type: message/rfc822\r\n\
+                     \r\n"
+                )
+                .bytes(),
+            );
+            message.extend(&data.email);
+        }
+    }
+    if !message.ends_with(b"\r\n") {
+        message.extend(b"\r\n");
+    }
+    message.extend(format!("--{boundary}--\r\n").bytes());
+    message
+}
+
+/// Format a reply as the value of a `Diagnostic-Code` field,
+/// with the lines of a multiline reply folded into a single field.
+fn diagnostic_code(reply: &Reply) -> String {
+    let mut code = reply.code.to_string();
+    if let Some(enhanced) = reply.enhanced {
+        code.push_str(&format!(" {enhanced}"));
+    }
+    let mut lines = reply.text.split('\n');
+    code.push_str(&format!(" {}", lines.next().unwrap_or_default()));
+    for line in lines {
+        code.push_str(&format!("\r\n {line}"));
+    }
+    code
+}
+
+/// Format a unix timestamp as an RFC 5322 date in UTC.
+pub(crate) fn format_date(timestamp: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct",
+        "Nov", "Dec",
+    ];
+    let days = timestamp / 86400;
+    let seconds = timestamp % 86400;
+    // convert days since the epoch to a civil date,
+    // see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} +0000",
+        DAYS[(days % 7) as usize],
+        MONTHS[(month - 1) as usize],
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{format_date, format_message, FailedRecipient};
+    use crate::{envelope::Recipient, smtp::Data, EnhancedCode, Reply};
+
+    #[test]
+    fn date() {
+        assert_eq!(format_date(0), "Thu, 01 Jan 1970 00:00:00 +0000");
+        assert_eq!(
+            format_date(1_709_251_199),
+            "Thu, 29 Feb 2024 23:59:59 +0000"
+        );
+    }
+
+    #[test]
+    fn multi_line_reply() {
+        let recipient = Recipient {
+            address: "missing@example.com".to_string(),
+            notify: None,
+            original_recipient: None,
+            parameters: Vec::new(),
+        };
+        let data = Data {
+            email: b"Subject: Hello\r\n\r\nHello\r\n".to_vec(),
+            helo_name: "client.example.com".to_string(),
+            extended: true,
+            client_ip: None,
+            authenticated: false,
+            user: None,
+            spf: None,
+            address_from: "sender@example.com".to_string(),
+            dsn_return: None,
+            dsn_envelope_id: None,
+            mail_parameters: Vec::new(),
+            recipients: Vec::new(),
+            bounced: vec![recipient.clone()],
+        };
+        let reply = Reply::new(
+            550,
+            Some(EnhancedCode::new(5, 1, 1)),
+            "No such user\nAction: delivered",
+        );
+        let failed = FailedRecipient {
+            recipient,
+            status: reply.enhanced.unwrap(),
+            reply,
+        };
+        let message = format_message(
+            &data,
+            "sender@example.com",
+            &[failed],
+            "127.0.0.1",
+            UNIX_EPOCH + Duration::from_secs(1),
+        );
+        let message = String::from_utf8(message).unwrap();
+        assert!(message.starts_with(
+            "From: Mail Delivery System <MAILER-DAEMON@[127.0.0.1]>\r\n"
+        ));
+        assert!(message.contains("Message-ID: <bounce-1-0@[127.0.0.1]>\r\n"));
+        assert!(message.contains(
+            "Diagnostic-Code: smtp; 550 5.1.1 No such user\r\n \
+             Action: delivered\r\n"
+        ));
+        assert!(!message.contains("\r\n550-"), "{message}");
+    }
+}
@@ -0,0 +1,49 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A clock to measure greylisting delays and rate limit windows with.
+#[derive(Clone, Debug)]
+pub enum Clock {
+    /// The system clock.
+    System,
+    /// A clock that only advances when told to,
+    /// so tests do not have to wait for delays to pass.
+    Manual(ManualClock),
+}
+
+impl Clock {
+    pub(crate) fn now(&self) -> Duration {
+        match self {
+            Self::System => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            Self::Manual(clock) => clock.elapsed(),
+        }
+    }
+}
+
+/// A clock controlled by tests, see [`Clock::Manual`].
+///
+/// Clones share the same time.
+#[derive(Clone, Default, Debug)]
+pub struct ManualClock {
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move the clock forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().expect("clock poisoned") += duration;
+    }
+
+    /// The total duration the clock was moved forward by.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().expect("clock poisoned")
+    }
+}
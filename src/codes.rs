@@ -0,0 +1,124 @@
+use regex::Regex;
+
+use crate::{html, links::Link};
+
+/// How to find a one-time code or token in an email,
+/// see [`crate::Email::extract_code`].
+#[derive(Clone, Debug)]
+pub enum CodePattern {
+    /// Match a regular expression against the text part,
+    /// then against the text of the html part.
+    ///
+    /// The code is the first capture group,
+    /// or the complete match if the expression has no groups.
+    Regex(Regex),
+    /// Take the value of a query parameter of a link,
+    /// such as `token` in `https://example.com/verify?token=abc`,
+    /// including the target of tracking redirects.
+    QueryParameter(String),
+}
+
+impl CodePattern {
+    /// Match a code of exactly the given number of digits,
+    /// which is not part of a longer word or number or of a URL.
+    pub fn digits(len: usize) -> Self {
+        Self::Regex(
+            Regex::new(&format!(
+                r"(?:^|[^\w/=&#.%-])(\d{{{len}}})(?:$|[^\w/.%-]|\.(?:$|\s))"
+            ))
+            .expect("invalid digits regex"),
+        )
+    }
+
+    /// Match a regular expression, see [`CodePattern::Regex`].
+    pub fn regex(pattern: &str) -> Result<Self, regex::Error> {
+        Regex::new(pattern).map(Self::Regex)
+    }
+
+    /// Take the value of a query parameter,
+    /// see [`CodePattern::QueryParameter`].
+    pub fn query(name: impl Into<String>) -> Self {
+        Self::QueryParameter(name.into())
+    }
+}
+
+impl Default for CodePattern {
+    /// A code of 6 digits.
+    fn default() -> Self {
+        Self::digits(6)
+    }
+}
+
+/// Find the first code matching the pattern.
+pub(crate) fn extract(
+    pattern: &CodePattern,
+    body_text: &str,
+    body_html: &str,
+    links: impl FnOnce() -> Vec<Link>,
+) -> Option<String> {
+    match pattern {
+        CodePattern::Regex(regex) => {
+            [body_text, &html::render_text(body_html, false)]
+                .into_iter()
+                .find_map(|text| {
+                    let captures = regex.captures(text)?;
+                    let code = captures.get(1).or_else(|| captures.get(0))?;
+                    Some(code.as_str().to_string())
+                })
+        }
+        CodePattern::QueryParameter(name) => links().iter().find_map(|link| {
+            link.query(name).or_else(|| {
+                let target = Link {
+                    url: link.target(),
+                    ..link.clone()
+                };
+                target.query(name)
+            })
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract, CodePattern};
+    use crate::links;
+
+    fn find(pattern: &CodePattern, text: &str, html: &str) -> Option<String> {
+        extract(pattern, text, html, || links::extract(html, text))
+    }
+
+    #[test]
+    fn digits() {
+        let pattern = CodePattern::default();
+        assert_eq!(
+            find(&pattern, "Order 1234567, your code is 042917.", ""),
+            Some("042917".to_string())
+        );
+        assert_eq!(
+            find(
+                &pattern,
+                "See https://example.com/orders/123456 or ?id=123456",
+                "<p style=\"color: #123456\">Code: <b>987654</b></p>",
+            ),
+            Some("987654".to_string())
+        );
+        assert_eq!(find(&pattern, "No code here", ""), None);
+    }
+
+    #[test]
+    fn regex_and_query() {
+        let pattern = CodePattern::regex(r"code: ([A-Z]{4}-[A-Z]{4})").unwrap();
+        assert_eq!(
+            find(&pattern, "Your code: ABCD-EFGH", ""),
+            Some("ABCD-EFGH".to_string())
+        );
+        let html = "<a href=\"https://click.example.com/?\
+                    u=https%3A%2F%2Fexample.com%2Fverify%3Ftoken%3Dxyz%252B1\">\
+                    Verify</a>";
+        assert_eq!(
+            find(&CodePattern::query("token"), "", html),
+            Some("xyz+1".to_string())
+        );
+        assert_eq!(find(&CodePattern::query("missing"), "", html), None);
+    }
+}
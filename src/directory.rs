@@ -0,0 +1,178 @@
+use crate::reply::{EnhancedCode, Reply};
+
+const OK: EnhancedCode = EnhancedCode::new(2, 0, 0);
+const CANNOT_VERIFY: EnhancedCode = EnhancedCode::new(2, 5, 2);
+const UNKNOWN: EnhancedCode = EnhancedCode::new(5, 1, 1);
+const AMBIGUOUS: EnhancedCode = EnhancedCode::new(5, 1, 4);
+const NOT_AVAILABLE: EnhancedCode = EnhancedCode::new(5, 5, 1);
+
+/// The users and mailing lists known to a server,
+/// used to answer `VRFY` and `EXPN` commands.
+#[derive(Clone, Default, Debug)]
+pub(crate) struct Directory {
+    users: Vec<User>,
+    mailing_lists: Vec<(String, Vec<String>)>,
+}
+
+#[derive(Clone, Debug)]
+struct User {
+    address: String,
+    name: Option<String>,
+}
+
+impl User {
+    /// Whether this user is identified by the `VRFY` argument,
+    /// which is either the address, its local part or the full name.
+    fn matches(&self, query: &str) -> bool {
+        let local_part = self
+            .address
+            .split_once('@')
+            .map(|(local_part, _)| local_part)
+            .unwrap_or(&self.address);
+        self.address.eq_ignore_ascii_case(query)
+            || local_part.eq_ignore_ascii_case(query)
+            || self
+                .name
+                .as_ref()
+                .is_some_and(|name| name.eq_ignore_ascii_case(query))
+    }
+
+    fn mailbox(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{name} <{}>", self.address),
+            None => format!("<{}>", self.address),
+        }
+    }
+}
+
+impl Directory {
+    pub fn add_user(&mut self, address: String, name: Option<String>) {
+        self.users.push(User { address, name });
+    }
+
+    pub fn add_mailing_list(&mut self, name: String, members: Vec<String>) {
+        self.mailing_lists.push((name, members));
+    }
+
+    /// The reply to a `VRFY` command with the given argument.
+    pub fn verify(&self, query: &str) -> Reply {
+        if self.users.is_empty() {
+            return Reply::new(
+                252,
+                Some(CANNOT_VERIFY),
+                "Cannot VRFY user, but will accept message",
+            );
+        }
+        let query = strip_brackets(query);
+        let users = self
+            .users
+            .iter()
+            .filter(|user| user.matches(query))
+            .collect::<Vec<_>>();
+        match users.as_slice() {
+            [] => Reply::new(550, Some(UNKNOWN), "User unknown"),
+            [user] => Reply::new(250, Some(OK), user.mailbox()),
+            users => {
+                let mut lines =
+                    vec!["Ambiguous; possibilities are".to_string()];
+                lines.extend(users.iter().map(|user| user.mailbox()));
+                Reply::new(553, Some(AMBIGUOUS), lines.join("\n"))
+            }
+        }
+    }
+
+    /// The reply to an `EXPN` command with the given argument.
+    pub fn expand(&self, query: &str) -> Reply {
+        if self.mailing_lists.is_empty() {
+            return Reply::new(502, Some(NOT_AVAILABLE), "EXPN not available");
+        }
+        let query = strip_brackets(query);
+        let members = self
+            .mailing_lists
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(query))
+            .map(|(_, members)| members);
+        match members {
+            Some(members) if !members.is_empty() => {
+                let lines = members
+                    .iter()
+                    .map(|member| {
+                        self.users
+                            .iter()
+                            .find(|user| {
+                                user.address.eq_ignore_ascii_case(member)
+                            })
+                            .map(|user| user.mailbox())
+                            .unwrap_or_else(|| format!("<{member}>"))
+                    })
+                    .collect::<Vec<_>>();
+                Reply::new(250, Some(OK), lines.join("\n"))
+            }
+            Some(_) => Reply::new(550, Some(UNKNOWN), "Mailing list empty"),
+            None => Reply::new(550, Some(UNKNOWN), "Mailing list unknown"),
+        }
+    }
+}
+
+fn strip_brackets(query: &str) -> &str {
+    query
+        .strip_prefix('<')
+        .and_then(|query| query.strip_suffix('>'))
+        .unwrap_or(query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Directory;
+
+    fn directory() -> Directory {
+        let mut directory = Directory::default();
+        directory.add_user(
+            "fred@example.com".to_string(),
+            Some("Fred Smith".to_string()),
+        );
+        directory.add_user("fred@other.example.com".to_string(), None);
+        directory.add_user("jane@example.com".to_string(), None);
+        directory.add_mailing_list(
+            "staff".to_string(),
+            vec![
+                "fred@example.com".to_string(),
+                "jane@example.com".to_string(),
+            ],
+        );
+        directory
+    }
+
+    #[test]
+    fn verify() {
+        let directory = directory();
+        assert_eq!(
+            directory.verify("Fred Smith").to_string(),
+            "250 2.0.0 Fred Smith <fred@example.com>\r\n"
+        );
+        assert_eq!(
+            directory.verify("<jane@example.com>").to_string(),
+            "250 2.0.0 <jane@example.com>\r\n"
+        );
+        assert_eq!(
+            directory.verify("fred").to_string(),
+            "553-5.1.4 Ambiguous; possibilities are\r\n\
+             553-5.1.4 Fred Smith <fred@example.com>\r\n\
+             553 5.1.4 <fred@other.example.com>\r\n"
+        );
+        assert_eq!(directory.verify("nobody").code, 550);
+        assert_eq!(Directory::default().verify("fred").code, 252);
+    }
+
+    #[test]
+    fn expand() {
+        let directory = directory();
+        assert_eq!(
+            directory.expand("staff").to_string(),
+            "250-2.0.0 Fred Smith <fred@example.com>\r\n\
+             250 2.0.0 <jane@example.com>\r\n"
+        );
+        assert_eq!(directory.expand("other").code, 550);
+        assert_eq!(Directory::default().expand("staff").code, 502);
+    }
+}
@@ -0,0 +1,588 @@
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64ct::{Base64, Encoding};
+use sha2::{Digest, Sha256};
+
+/// Public keys to verify DKIM signatures with,
+/// standing in for the DNS records they would be published in.
+#[derive(Clone, Default, Debug)]
+pub struct DkimKeys {
+    records: HashMap<(String, String), String>,
+}
+
+impl DkimKeys {
+    /// An empty key table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the key record for the given selector and domain.
+    ///
+    /// The record is given as it would be published in the DNS TXT record
+    /// at `<selector>._domainkey.<domain>`,
+    /// such as `v=DKIM1; k=ed25519; p=<base64 public key>`.
+    /// Both `rsa` and `ed25519` keys are supported.
+    pub fn insert(
+        &mut self,
+        selector: impl Into<String>,
+        domain: impl Into<String>,
+        record: impl Into<String>,
+    ) {
+        self.records.insert(
+            (
+                selector.into().to_ascii_lowercase(),
+                domain.into().to_ascii_lowercase(),
+            ),
+            record.into(),
+        );
+    }
+
+    fn get(&self, selector: &str, domain: &str) -> Option<&str> {
+        self.records
+            .get(&(selector.to_ascii_lowercase(), domain.to_ascii_lowercase()))
+            .map(String::as_str)
+    }
+}
+
+/// The result of verifying a single `DKIM-Signature` header.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct DkimSignature {
+    /// The signing domain, from the `d=` tag.
+    ///
+    /// This is empty if the signature is malformed.
+    pub domain: String,
+
+    /// The selector of the key, from the `s=` tag.
+    ///
+    /// This is empty if the signature is malformed.
+    pub selector: String,
+
+    /// Whether the signature is valid.
+    pub result: DkimResult,
+}
+
+/// Whether a [`DkimSignature`] is valid.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DkimResult {
+    /// The signature is valid.
+    Pass,
+    /// The signature is invalid, for the given reason.
+    Fail(DkimFailure),
+}
+
+impl DkimResult {
+    /// Whether the signature is valid.
+    pub fn is_pass(&self) -> bool {
+        matches!(self, Self::Pass)
+    }
+}
+
+/// Why a [`DkimSignature`] is invalid.
+#[derive(thiserror::Error, Clone, PartialEq, Eq, Debug)]
+pub enum DkimFailure {
+    #[error("malformed signature: {0}")]
+    Malformed(String),
+    #[error("unsupported algorithm {0:?}")]
+    UnsupportedAlgorithm(String),
+    #[error("no key for this selector and domain")]
+    KeyNotFound,
+    #[error("the key has been revoked")]
+    KeyRevoked,
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+    #[error("the signature has expired")]
+    Expired,
+    #[error("the body hash does not match")]
+    BodyHashMismatch,
+    #[error("the signature does not match")]
+    SignatureMismatch,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Algorithm {
+    RsaSha256,
+    Ed25519Sha256,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+/// Verify all `DKIM-Signature` headers of a raw message, in order.
+pub(crate) fn verify(message: &[u8], keys: &DkimKeys) -> Vec<DkimSignature> {
+    let (headers, body) = split_message(message);
+    headers
+        .iter()
+        .filter(|header| {
+            header_name(header).eq_ignore_ascii_case("DKIM-Signature")
+        })
+        .map(|signature| verify_signature(signature, &headers, body, keys))
+        .collect()
+}
+
+fn verify_signature(
+    signature: &[u8],
+    headers: &[&[u8]],
+    body: &[u8],
+    keys: &DkimKeys,
+) -> DkimSignature {
+    let value = String::from_utf8_lossy(header_value(signature));
+    let mut result = DkimSignature {
+        domain: String::new(),
+        selector: String::new(),
+        result: DkimResult::Pass,
+    };
+    let tags = match parse_tags(&value) {
+        Ok(tags) => tags,
+        Err(failure) => {
+            result.result = DkimResult::Fail(failure);
+            return result;
+        }
+    };
+    if let (Some(domain), Some(selector)) = (tags.get("d"), tags.get("s")) {
+        result.domain = domain.to_string();
+        result.selector = selector.to_string();
+    }
+    if let Err(failure) = check(signature, &tags, headers, body, keys) {
+        result.result = DkimResult::Fail(failure);
+    }
+    result
+}
+
+fn check(
+    signature: &[u8],
+    tags: &HashMap<&str, &str>,
+    headers: &[&[u8]],
+    body: &[u8],
+    keys: &DkimKeys,
+) -> Result<(), DkimFailure> {
+    let tag = |name: &str| {
+        tags.get(name).copied().ok_or_else(|| {
+            DkimFailure::Malformed(format!("missing {name}= tag"))
+        })
+    };
+    if tag("v")? != "1" {
+        return Err(DkimFailure::Malformed("unsupported version".to_string()));
+    }
+    let algorithm = match tag("a")? {
+        "rsa-sha256" => Algorithm::RsaSha256,
+        "ed25519-sha256" => Algorithm::Ed25519Sha256,
+        other => {
+            return Err(DkimFailure::UnsupportedAlgorithm(other.to_string()))
+        }
+    };
+    let domain = tag("d")?;
+    let selector = tag("s")?;
+    let signed_headers =
+        tag("h")?.split(':').map(str::trim).collect::<Vec<_>>();
+    if !signed_headers
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case("From"))
+    {
+        return Err(DkimFailure::Malformed(
+            "the From header is not signed".to_string(),
+        ));
+    }
+    let (header_canon, body_canon) = parse_canonicalization(
+        tags.get("c").copied().unwrap_or("simple/simple"),
+    )?;
+    if let Some(identity) = tags.get("i") {
+        let identity_domain = identity.rsplit('@').next().unwrap_or_default();
+        let suffix = format!(".{domain}").to_ascii_lowercase();
+        if !identity_domain.eq_ignore_ascii_case(domain)
+            && !identity_domain.to_ascii_lowercase().ends_with(&suffix)
+        {
+            return Err(DkimFailure::Malformed(
+                "the i= tag is not within the d= domain".to_string(),
+            ));
+        }
+    }
+    if let Some(expiration) = tags.get("x") {
+        let expiration = expiration.parse::<u64>().map_err(|_| {
+            DkimFailure::Malformed("invalid x= tag".to_string())
+        })?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if now > expiration {
+            return Err(DkimFailure::Expired);
+        }
+    }
+    let body_hash = decode_base64(tag("bh")?)
+        .ok_or_else(|| DkimFailure::Malformed("invalid bh= tag".to_string()))?;
+    let signature_data = decode_base64(tag("b")?)
+        .ok_or_else(|| DkimFailure::Malformed("invalid b= tag".to_string()))?;
+
+    let record = keys.get(selector, domain).ok_or(DkimFailure::KeyNotFound)?;
+    let key = parse_key(record, algorithm)?;
+
+    let mut body = canonicalize_body(body, body_canon);
+    if let Some(length) = tags.get("l") {
+        let length = length.parse::<usize>().map_err(|_| {
+            DkimFailure::Malformed("invalid l= tag".to_string())
+        })?;
+        if length > body.len() {
+            return Err(DkimFailure::BodyHashMismatch);
+        }
+        body.truncate(length);
+    }
+    if Sha256::digest(&body).as_slice() != body_hash {
+        return Err(DkimFailure::BodyHashMismatch);
+    }
+
+    let mut data = Vec::new();
+    for header in select_headers(headers, &signed_headers) {
+        data.extend(canonicalize_header(header, header_canon));
+    }
+    let unsigned = remove_signature(signature);
+    let mut unsigned = canonicalize_header(&unsigned, header_canon);
+    unsigned.truncate(unsigned.len() - 2);
+    data.extend(unsigned);
+
+    let valid = match key {
+        Key::Rsa(key) => {
+            use rsa::signature::Verifier;
+            let key = rsa::pkcs1v15::VerifyingKey::<Sha256>::new(key);
+            rsa::pkcs1v15::Signature::try_from(signature_data.as_slice())
+                .and_then(|signature| key.verify(&data, &signature))
+                .is_ok()
+        }
+        Key::Ed25519(key) => {
+            use ed25519_dalek::Verifier;
+            ed25519_dalek::Signature::from_slice(&signature_data)
+                .and_then(|signature| {
+                    key.verify(&Sha256::digest(&data), &signature)
+                })
+                .is_ok()
+        }
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(DkimFailure::SignatureMismatch)
+    }
+}
+
+enum Key {
+    Rsa(rsa::RsaPublicKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+fn parse_key(record: &str, algorithm: Algorithm) -> Result<Key, DkimFailure> {
+    let invalid = |reason: &str| DkimFailure::InvalidKey(reason.to_string());
+    let tags = parse_tags(record).map_err(|_| invalid("malformed record"))?;
+    if tags.get("v").is_some_and(|version| *version != "DKIM1") {
+        return Err(invalid("unsupported version"));
+    }
+    let data = tags.get("p").ok_or_else(|| invalid("missing p= tag"))?;
+    if data.is_empty() {
+        return Err(DkimFailure::KeyRevoked);
+    }
+    let data = decode_base64(data).ok_or_else(|| invalid("invalid p= tag"))?;
+    match (tags.get("k").copied().unwrap_or("rsa"), algorithm) {
+        ("rsa", Algorithm::RsaSha256) => {
+            use rsa::{pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey};
+            rsa::RsaPublicKey::from_public_key_der(&data)
+                .or_else(|_| rsa::RsaPublicKey::from_pkcs1_der(&data))
+                .map(Key::Rsa)
+                .map_err(|_| invalid("invalid rsa key"))
+        }
+        ("ed25519", Algorithm::Ed25519Sha256) => data
+            .as_slice()
+            .try_into()
+            .ok()
+            .and_then(|data| ed25519_dalek::VerifyingKey::from_bytes(data).ok())
+            .map(Key::Ed25519)
+            .ok_or_else(|| invalid("invalid ed25519 key")),
+        _ => Err(invalid("the key type does not match the algorithm")),
+    }
+}
+
+fn parse_canonicalization(
+    value: &str,
+) -> Result<(Canonicalization, Canonicalization), DkimFailure> {
+    let parse = |name: &str| match name {
+        "simple" => Ok(Canonicalization::Simple),
+        "relaxed" => Ok(Canonicalization::Relaxed),
+        _ => Err(DkimFailure::Malformed(format!(
+            "unsupported canonicalization {name:?}"
+        ))),
+    };
+    match value.split_once('/') {
+        Some((header, body)) => Ok((parse(header)?, parse(body)?)),
+        None => Ok((parse(value)?, Canonicalization::Simple)),
+    }
+}
+
+/// Parse a tag list such as `v=1; a=rsa-sha256`,
+/// removing all whitespace from the values of base64 tags.
+fn parse_tags(value: &str) -> Result<HashMap<&str, &str>, DkimFailure> {
+    let mut tags = HashMap::new();
+    for tag in value.split(';') {
+        if tag.trim().is_empty() {
+            continue;
+        }
+        let (name, value) = tag.split_once('=').ok_or_else(|| {
+            DkimFailure::Malformed(format!("invalid tag {:?}", tag.trim()))
+        })?;
+        let name = name.trim();
+        if tags.insert(name, value.trim()).is_some() {
+            return Err(DkimFailure::Malformed(format!(
+                "duplicate {name}= tag"
+            )));
+        }
+    }
+    Ok(tags)
+}
+
+fn decode_base64(value: &str) -> Option<Vec<u8>> {
+    let value = value
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<String>();
+    Base64::decode_vec(&value).ok()
+}
+
+/// Split a raw message into its header fields,
+/// each including any folded lines and the final "\r\n",
+/// and its body.
+fn split_message(message: &[u8]) -> (Vec<&[u8]>, &[u8]) {
+    let mut headers = Vec::<&[u8]>::new();
+    let mut start = 0;
+    let mut position = 0;
+    while position < message.len() {
+        let end = message[position..]
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map_or(message.len(), |end| position + end + 1);
+        let line = &message[position..end];
+        if line == b"\r\n" || line == b"\n" {
+            if start < position {
+                headers.push(&message[start..position]);
+            }
+            return (headers, &message[end..]);
+        }
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            if start < position {
+                headers.push(&message[start..position]);
+            }
+            start = position;
+        }
+        position = end;
+    }
+    if start < position {
+        headers.push(&message[start..position]);
+    }
+    (headers, &[])
+}
+
+fn header_name(header: &[u8]) -> &str {
+    let name = header
+        .iter()
+        .position(|&byte| byte == b':')
+        .map_or(header, |colon| &header[..colon]);
+    std::str::from_utf8(name).unwrap_or_default().trim()
+}
+
+fn header_value(header: &[u8]) -> &[u8] {
+    header
+        .iter()
+        .position(|&byte| byte == b':')
+        .map_or(&[], |colon| &header[colon + 1..])
+}
+
+/// Select the signed headers, taking repeated headers from the bottom up.
+fn select_headers<'a>(
+    headers: &[&'a [u8]],
+    signed_headers: &[&str],
+) -> Vec<&'a [u8]> {
+    let mut used = vec![false; headers.len()];
+    let mut selected = Vec::new();
+    for name in signed_headers {
+        let found = (0..headers.len()).rev().find(|&index| {
+            !used[index]
+                && header_name(headers[index]).eq_ignore_ascii_case(name)
+        });
+        if let Some(index) = found {
+            used[index] = true;
+            selected.push(headers[index]);
+        }
+    }
+    selected
+}
+
+/// Empty the value of the `b=` tag of a `DKIM-Signature` header.
+fn remove_signature(signature: &[u8]) -> Vec<u8> {
+    let colon = signature
+        .iter()
+        .position(|&byte| byte == b':')
+        .map_or(0, |colon| colon + 1);
+    let mut result = signature[..colon].to_vec();
+    let mut first = true;
+    for tag in signature[colon..].split(|&byte| byte == b';') {
+        if !first {
+            result.push(b';');
+        }
+        first = false;
+        match tag.iter().position(|&byte| byte == b'=') {
+            Some(equals) if tag[..equals].trim_ascii() == b"b" => {
+                result.extend(&tag[..=equals]);
+                // keep the terminating "\r\n" if this is the last tag
+                if tag.ends_with(b"\r\n") {
+                    result.extend(b"\r\n");
+                }
+            }
+            _ => result.extend(tag),
+        }
+    }
+    result
+}
+
+fn canonicalize_header(
+    header: &[u8],
+    canonicalization: Canonicalization,
+) -> Vec<u8> {
+    match canonicalization {
+        Canonicalization::Simple => {
+            let mut header = header.to_vec();
+            if !header.ends_with(b"\r\n") {
+                header.extend(b"\r\n");
+            }
+            header
+        }
+        Canonicalization::Relaxed => {
+            let mut result =
+                header_name(header).to_ascii_lowercase().into_bytes();
+            result.push(b':');
+            let value = header_value(header)
+                .iter()
+                .copied()
+                .filter(|&byte| byte != b'\r' && byte != b'\n')
+                .collect::<Vec<_>>();
+            result.extend(compress_whitespace(trim(&value)));
+            result.extend(b"\r\n");
+            result
+        }
+    }
+}
+
+fn canonicalize_body(
+    body: &[u8],
+    canonicalization: Canonicalization,
+) -> Vec<u8> {
+    let mut lines = body.split(|&byte| byte == b'\n').collect::<Vec<_>>();
+    // the body is split after the final line break
+    if lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    let mut lines = lines
+        .into_iter()
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .map(|line| match canonicalization {
+            Canonicalization::Simple => line.to_vec(),
+            Canonicalization::Relaxed => compress_whitespace(trim_end(line)),
+        })
+        .collect::<Vec<_>>();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    if lines.is_empty() && canonicalization == Canonicalization::Simple {
+        return b"\r\n".to_vec();
+    }
+    let mut result = Vec::with_capacity(body.len());
+    for line in lines {
+        result.extend(line);
+        result.extend(b"\r\n");
+    }
+    result
+}
+
+fn is_whitespace(byte: &u8) -> bool {
+    *byte == b' ' || *byte == b'\t'
+}
+
+fn trim(value: &[u8]) -> &[u8] {
+    let start = value
+        .iter()
+        .position(|byte| !is_whitespace(byte))
+        .unwrap_or(value.len());
+    trim_end(&value[start..])
+}
+
+fn trim_end(value: &[u8]) -> &[u8] {
+    let end = value
+        .iter()
+        .rposition(|byte| !is_whitespace(byte))
+        .map_or(0, |end| end + 1);
+    &value[..end]
+}
+
+/// Replace each run of spaces and tabs with a single space.
+fn compress_whitespace(value: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(value.len());
+    for &byte in value {
+        if is_whitespace(&byte) {
+            if result.last() != Some(&b' ') {
+                result.push(b' ');
+            }
+        } else {
+            result.push(byte);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        canonicalize_body, canonicalize_header, remove_signature,
+        Canonicalization::{Relaxed, Simple},
+    };
+
+    #[test]
+    fn canonicalization() {
+        // examples from RFC 6376, section 3.4.5
+        let headers: [&[u8]; 2] = [b"A: X\r\n", b"B : Y\t\r\n\tZ  \r\n"];
+        let relaxed = headers
+            .iter()
+            .flat_map(|header| canonicalize_header(header, Relaxed))
+            .collect::<Vec<_>>();
+        assert_eq!(relaxed, b"a:X\r\nb:Y Z\r\n");
+        let simple = headers
+            .iter()
+            .flat_map(|header| canonicalize_header(header, Simple))
+            .collect::<Vec<_>>();
+        assert_eq!(simple, b"A: X\r\nB : Y\t\r\n\tZ  \r\n");
+
+        let body = b" C \r\nD \t E\r\n\r\n\r\n";
+        assert_eq!(canonicalize_body(body, Relaxed), b" C\r\nD E\r\n");
+        assert_eq!(canonicalize_body(body, Simple), b" C \r\nD \t E\r\n");
+        assert_eq!(canonicalize_body(b"", Simple), b"\r\n");
+        assert_eq!(canonicalize_body(b"\r\n", Relaxed), b"");
+    }
+
+    #[test]
+    fn remove_signature_value() {
+        assert_eq!(
+            remove_signature(
+                b"DKIM-Signature: a=x; bh=YQ==; b=YWJj\r\n d==\r\n"
+            ),
+            b"DKIM-Signature: a=x; bh=YQ==; b=\r\n"
+        );
+        assert_eq!(
+            remove_signature(b"DKIM-Signature: b = YWJj ; d=a\r\n"),
+            b"DKIM-Signature: b =; d=a\r\n"
+        );
+        assert_eq!(
+            remove_signature(b"DKIM-Signature: d=a;\r\n b=YWJj\r\n ZGVm\r\n"),
+            b"DKIM-Signature: d=a;\r\n b=\r\n"
+        );
+    }
+}
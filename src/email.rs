@@ -1,9 +1,26 @@
-use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    alternative::TextMismatch,
+    codes::CodePattern,
+    envelope::{DsnReturn, Recipient},
+    headers::{parse_message_ids, Headers},
+    links::Link,
+    spf::SpfResult,
+    transcript::Transcript,
+};
 
 /// An parsed email as received by the server.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 #[non_exhaustive]
 pub struct Email {
+    /// The name the client identified itself with
+    /// in the `EHLO` or `HELO` command.
+    ///
+    /// This is a domain name or an address literal
+    /// such as `[127.0.0.1]`.
+    pub helo_name: String,
+
     /// The email address of the sender.
     ///
     /// This is the address as received in the SMTP exchange
@@ -14,38 +31,229 @@ pub struct Email {
     ///
     /// This is the address as received in the SMTP exchange
     /// and does not include a name.
+    /// If multiple recipients were given,
+    /// this is the first of [`Email::recipients`].
     pub address_to: String,
 
+    /// All recipients as received in the SMTP exchange,
+    /// including their delivery status notification parameters.
+    ///
+    /// This excludes recipients that were bounced,
+    /// see [`crate::Server::set_bounces`].
+    pub recipients: Vec<Recipient>,
+
+    /// The `RET` delivery status notification parameter
+    /// of the `MAIL FROM` command, if any.
+    pub dsn_return: Option<DsnReturn>,
+
+    /// The `ENVID` delivery status notification parameter
+    /// of the `MAIL FROM` command, if any.
+    pub dsn_envelope_id: Option<String>,
+
+    /// Any other parameters of the `MAIL FROM` command,
+    /// such as `SIZE` or `BODY`,
+    /// with uppercase keywords and values as received.
+    pub mail_parameters: Vec<(String, Option<String>)>,
+
     /// The subject of this email,
     /// taken from the headers.
     pub subject: String,
 
-    /// The map of headers.
-    pub headers: HashMap<String, String>,
+    /// The headers, in the order they were received.
+    pub headers: Headers,
 
     /// The text part of this email.
     pub body_text: String,
 
     /// The html part of this email.
     pub body_html: String,
+
+    /// The MIME parts of this email, in order,
+    /// which are the text part followed by the html part.
+    pub parts: Vec<Part>,
+
+    /// The result of the SPF check of the envelope sender,
+    /// if enabled with [`crate::Server::set_spf`].
+    pub spf: Option<SpfResult>,
+
+    /// The transcript of the connection this email was received over,
+    /// up to and including this email.
+    pub transcript: Transcript,
+
+    /// The results of verifying each `DKIM-Signature` header, in order,
+    /// with the keys set with [`crate::Server::set_dkim_keys`].
+    ///
+    /// This field is only available with the `dkim` feature.
+    #[cfg(feature = "dkim")]
+    pub dkim: Vec<crate::DkimSignature>,
 }
 
 impl Email {
     pub(crate) fn parse(data: crate::smtp::Data) -> Result<Self, ParseError> {
         let mail = mailparse::parse_mail(&data.email)?;
-        Ok(convert_email(data.address_from, data.address_to, mail)?)
+        let mut email =
+            convert_email(data.address_from, &data.recipients, mail)?;
+        email.helo_name = data.helo_name;
+        email.recipients = data.recipients;
+        email.dsn_return = data.dsn_return;
+        email.dsn_envelope_id = data.dsn_envelope_id;
+        email.mail_parameters = data.mail_parameters;
+        email.spf = data.spf;
+        Ok(email)
+    }
+
+    /// Get the recipient with the given email address, if any.
+    ///
+    /// The address is compared ignoring ASCII case,
+    /// like [`crate::Matcher::Exact`].
+    pub fn recipient(&self, address: &str) -> Option<&Recipient> {
+        self.recipients
+            .iter()
+            .find(|recipient| recipient.address.eq_ignore_ascii_case(address))
     }
 
     /// Get the complete `From` header
-    /// which includes the name and email address.
-    pub fn get_from(&self) -> &str {
-        self.headers.get("From").unwrap()
+    /// which includes the name and email address, if any.
+    pub fn get_from(&self) -> Option<&str> {
+        self.headers.get("From")
     }
 
     /// Get the complete `To` header
-    /// which includes the name and email address.
-    pub fn get_to(&self) -> &str {
-        self.headers.get("To").unwrap()
+    /// which includes the name and email address, if any.
+    pub fn get_to(&self) -> Option<&str> {
+        self.headers.get("To")
+    }
+
+    /// Get the time from the `Date` header,
+    /// if present and valid.
+    pub fn date(&self) -> Option<SystemTime> {
+        let timestamp = mailparse::dateparse(self.headers.get("Date")?).ok()?;
+        let seconds = u64::try_from(timestamp).ok()?;
+        Some(UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+
+    /// Get the message ID from the `Message-ID` header, if any.
+    ///
+    /// Message IDs are returned without the enclosing angle brackets,
+    /// for example `1234@example.com`.
+    pub fn message_id(&self) -> Option<String> {
+        parse_message_ids(self.headers.get("Message-ID")?)
+            .into_iter()
+            .next()
+    }
+
+    /// Get the message IDs from the `In-Reply-To` header,
+    /// see [`Email::message_id`].
+    pub fn in_reply_to(&self) -> Vec<String> {
+        self.headers
+            .get("In-Reply-To")
+            .map(parse_message_ids)
+            .unwrap_or_default()
+    }
+
+    /// Get the message IDs from the `References` header, oldest first,
+    /// see [`Email::message_id`].
+    pub fn references(&self) -> Vec<String> {
+        self.headers
+            .get("References")
+            .map(parse_message_ids)
+            .unwrap_or_default()
+    }
+
+    /// Render the html part as plain text,
+    /// with the URL of each link following its text in parentheses.
+    pub fn html_to_text(&self) -> String {
+        crate::html::render_text(&self.body_html, true)
+    }
+
+    /// Compare the text part with the html part,
+    /// returning the paragraphs and links missing from either.
+    ///
+    /// Use this to catch a text alternative that went stale
+    /// after changes to the html part.
+    /// Nothing is reported if either part is missing.
+    pub fn check_text_alternative(&self) -> Vec<TextMismatch> {
+        crate::alternative::check(&self.body_text, &self.body_html)
+    }
+
+    /// Get all links in this email,
+    /// those in the html part followed by those in the text part.
+    ///
+    /// Links in the html part are taken from the `href` attribute
+    /// of `a` and `area` elements, with their text.
+    /// Links in the text part are all `http` and `https` URLs.
+    pub fn links(&self) -> Vec<Link> {
+        crate::links::extract(&self.body_html, &self.body_text)
+    }
+
+    /// Get the first link whose URL, or the target of a tracking redirect,
+    /// matches the regular expression, see [`Email::links`]
+    /// and [`Link::target`].
+    pub fn find_link(&self, regex: &regex::Regex) -> Option<Link> {
+        self.links().into_iter().find(|link| {
+            regex.is_match(&link.url) || regex.is_match(&link.target())
+        })
+    }
+
+    /// Get the first one-time code or token matching the pattern,
+    /// see [`CodePattern`].
+    pub fn extract_code(&self, pattern: &CodePattern) -> Option<String> {
+        crate::codes::extract(pattern, &self.body_text, &self.body_html, || {
+            self.links()
+        })
+    }
+
+    /// Get the first code of 6 digits, such as a one-time password,
+    /// see [`CodePattern::default`].
+    pub fn code(&self) -> Option<String> {
+        self.extract_code(&CodePattern::default())
+    }
+
+    /// Get a key identifying the thread this email belongs to.
+    ///
+    /// This is the message ID of the first message in the thread,
+    /// taken from the `References` or `In-Reply-To` header,
+    /// or the message ID of this email if it starts a new thread.
+    pub fn thread_key(&self) -> Option<String> {
+        self.references()
+            .into_iter()
+            .chain(self.in_reply_to())
+            .next()
+            .or_else(|| self.message_id())
+    }
+}
+
+/// A MIME part of an [`Email`].
+///
+/// The body of each part is decoded from its transfer encoding
+/// and charset into UTF-8.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Part {
+    /// The content type, such as `text/plain`.
+    pub mimetype: String,
+
+    /// The charset declared in the content type in lowercase,
+    /// such as `iso-8859-1`, or `us-ascii` if none was declared.
+    pub charset: String,
+
+    /// The `Content-Transfer-Encoding` in lowercase,
+    /// such as `base64`, or `7bit` if none was declared.
+    pub transfer_encoding: String,
+}
+
+impl Part {
+    fn parse(part: &mailparse::ParsedMail) -> Self {
+        use mailparse::MailHeaderMap;
+        Self {
+            mimetype: part.ctype.mimetype.clone(),
+            charset: part.ctype.charset.to_ascii_lowercase(),
+            transfer_encoding: part
+                .headers
+                .get_first_value("Content-Transfer-Encoding")
+                .map(|encoding| encoding.trim().to_ascii_lowercase())
+                .unwrap_or_else(|| "7bit".to_string()),
+        }
     }
 }
 
@@ -79,6 +287,8 @@ pub enum ConversionError {
     MisingSubject,
     #[error("multiple `Subject` headers")]
     MultipleSubjects(Vec<String>),
+    #[error("undecodable body: {0}")]
+    UndecodableBody(mailparse::MailParseError),
     #[error("unexpected part count; expected 2, received {0}")]
     UnexpectedPartCount(usize),
     #[error(
@@ -91,9 +301,12 @@ pub enum ConversionError {
 }
 
 /// Convert a [`mailparse::ParsedMail`] into an [`Email`].
+///
+/// At least one of the recipients must be found
+/// in the `To`, `Cc` or `Bcc` header.
 fn convert_email(
     address_from: String,
-    address_to: String,
+    recipients: &[Recipient],
     mail: mailparse::ParsedMail,
 ) -> Result<Email, ConversionError> {
     use mailparse::MailHeaderMap;
@@ -115,7 +328,19 @@ fn convert_email(
     } else {
         to_addrs.pop().ok_or(ConversionError::MisingToAddress)?
     };
-    if !to_addr.contains(&format!("<{address_to}>")) {
+    let header_addrs = ["To", "Cc", "Bcc"]
+        .into_iter()
+        .flat_map(|name| mail.headers.get_all_values(name))
+        .collect::<Vec<_>>();
+    let found = recipients.iter().any(|recipient| {
+        let address = format!("<{}>", recipient.address);
+        header_addrs.iter().any(|addrs| addrs.contains(&address))
+    });
+    let address_to = recipients
+        .first()
+        .map(|recipient| recipient.address.clone())
+        .unwrap_or_default();
+    if !found {
         return Err(ConversionError::ToAddressMismatch {
             smtp: address_to,
             email: to_addr,
@@ -134,8 +359,12 @@ fn convert_email(
     if mail.subparts.len() != 2 {
         return Err(ConversionError::UnexpectedPartCount(mail.subparts.len()));
     }
-    let part1 = mail.subparts[0].get_body().unwrap();
-    let part2 = mail.subparts[1].get_body().unwrap();
+    let part1 = mail.subparts[0]
+        .get_body()
+        .map_err(ConversionError::UndecodableBody)?;
+    let part2 = mail.subparts[1]
+        .get_body()
+        .map_err(ConversionError::UndecodableBody)?;
     let part1_mime = mail.subparts[0].ctype.mimetype.to_string();
     let part2_mime = mail.subparts[1].ctype.mimetype.to_string();
     if part1_mime != "text/plain" {
@@ -151,15 +380,21 @@ fn convert_email(
         });
     }
     Ok(Email {
+        helo_name: String::new(),
         address_from,
         address_to,
+        recipients: Vec::new(),
+        dsn_return: None,
+        dsn_envelope_id: None,
+        mail_parameters: Vec::new(),
         subject,
-        headers: mail
-            .headers
-            .into_iter()
-            .map(|header| (header.get_key(), header.get_value()))
-            .collect(),
+        headers: Headers::parse(&mail.headers),
         body_text: part1,
         body_html: part2,
+        parts: mail.subparts.iter().map(Part::parse).collect(),
+        spf: None,
+        transcript: Transcript::default(),
+        #[cfg(feature = "dkim")]
+        dkim: Vec::new(),
     })
 }
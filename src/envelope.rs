@@ -0,0 +1,233 @@
+/// The `RET` parameter of a `MAIL FROM` command (RFC 3461),
+/// which specifies how much of the message
+/// to include in a failure notification.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DsnReturn {
+    /// Return the full message (`RET=FULL`).
+    Full,
+    /// Return only the headers (`RET=HDRS`).
+    Headers,
+}
+
+/// A condition in the `NOTIFY` parameter
+/// of a `RCPT TO` command (RFC 3461).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DsnNotify {
+    Never,
+    Success,
+    Failure,
+    Delay,
+}
+
+/// A recipient as received in a `RCPT TO` command.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Recipient {
+    /// The email address of the recipient.
+    pub address: String,
+
+    /// The conditions on which a delivery status notification
+    /// was requested, taken from the `NOTIFY` parameter.
+    ///
+    /// This is `None` if the parameter was not present.
+    pub notify: Option<Vec<DsnNotify>>,
+
+    /// The original recipient, taken from the `ORCPT` parameter.
+    ///
+    /// This includes the address type,
+    /// for example `rfc822;user@example.com`.
+    pub original_recipient: Option<String>,
+
+    /// Any other parameters, such as those of extensions
+    /// advertised with [`crate::Server::set_capabilities`],
+    /// with uppercase keywords and values as received.
+    pub parameters: Vec<(String, Option<String>)>,
+}
+
+/// The parameters of a `MAIL FROM` command.
+#[derive(Default, Debug)]
+pub(crate) struct MailParameters {
+    pub dsn_return: Option<DsnReturn>,
+    pub dsn_envelope_id: Option<String>,
+    /// Any other parameters, such as `SIZE` or `BODY`.
+    pub other: Vec<(String, Option<String>)>,
+}
+
+/// Parse the parameters following the address of a `MAIL FROM` command.
+///
+/// Returns `None` if any parameter is invalid.
+pub(crate) fn parse_mail_parameters(params: &str) -> Option<MailParameters> {
+    let mut parsed = MailParameters::default();
+    for (keyword, value) in split_parameters(params)? {
+        match keyword.as_str() {
+            "RET" if parsed.dsn_return.is_none() => {
+                parsed.dsn_return =
+                    Some(match value?.to_ascii_uppercase().as_str() {
+                        "FULL" => DsnReturn::Full,
+                        "HDRS" => DsnReturn::Headers,
+                        _ => return None,
+                    });
+            }
+            "ENVID" if parsed.dsn_envelope_id.is_none() => {
+                parsed.dsn_envelope_id = Some(decode_xtext(value?)?);
+            }
+            "RET" | "ENVID" => return None,
+            _ => parsed.other.push((keyword, value.map(str::to_string))),
+        }
+    }
+    Some(parsed)
+}
+
+/// Parse the parameters following the address of a `RCPT TO` command.
+///
+/// Returns `None` if any parameter is invalid.
+pub(crate) fn parse_rcpt_parameters(
+    address: String,
+    params: &str,
+) -> Option<Recipient> {
+    let mut parsed = Recipient {
+        address,
+        notify: None,
+        original_recipient: None,
+        parameters: Vec::new(),
+    };
+    for (keyword, value) in split_parameters(params)? {
+        match keyword.as_str() {
+            "NOTIFY" if parsed.notify.is_none() => {
+                let notify = value?
+                    .split(',')
+                    .map(|value| match value.to_ascii_uppercase().as_str() {
+                        "NEVER" => Some(DsnNotify::Never),
+                        "SUCCESS" => Some(DsnNotify::Success),
+                        "FAILURE" => Some(DsnNotify::Failure),
+                        "DELAY" => Some(DsnNotify::Delay),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                if notify.contains(&DsnNotify::Never) && notify.len() > 1 {
+                    return None;
+                }
+                parsed.notify = Some(notify);
+            }
+            "ORCPT" if parsed.original_recipient.is_none() => {
+                let (addr_type, addr) = value?.split_once(';')?;
+                parsed.original_recipient =
+                    Some(format!("{addr_type};{}", decode_xtext(addr)?));
+            }
+            "NOTIFY" | "ORCPT" => return None,
+            _ => {
+                parsed.parameters.push((keyword, value.map(str::to_string)));
+            }
+        }
+    }
+    Some(parsed)
+}
+
+/// Split space separated `KEYWORD=value` or `KEYWORD` parameters
+/// into uppercase keywords and values.
+///
+/// Returns `None` if any keyword is invalid, see RFC 5321.
+fn split_parameters(params: &str) -> Option<Vec<(String, Option<&str>)>> {
+    params
+        .split(' ')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (keyword, value) = match param.split_once('=') {
+                Some((keyword, value)) => (keyword, Some(value)),
+                None => (param, None),
+            };
+            let valid = keyword
+                .starts_with(|c: char| c.is_ascii_alphanumeric())
+                && keyword
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-');
+            valid.then(|| (keyword.to_ascii_uppercase(), value))
+        })
+        .collect()
+}
+
+/// Decode an `xtext` value (RFC 3461, section 4),
+/// where special characters are encoded as `+XX`.
+fn decode_xtext(value: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '+' {
+            let hex: String = chars.by_ref().take(2).collect();
+            if hex.len() != 2 {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(&hex, 16).ok()? as char);
+        } else if c.is_ascii_graphic() && c != '=' {
+            decoded.push(c);
+        } else {
+            return None;
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_mail_parameters, parse_rcpt_parameters, DsnNotify, DsnReturn,
+    };
+
+    #[test]
+    fn parse_mail_dsn() {
+        let parsed = parse_mail_parameters(" RET=HDRS ENVID=QQ+2B314159")
+            .expect("invalid parameters");
+        assert_eq!(parsed.dsn_return, Some(DsnReturn::Headers));
+        assert_eq!(parsed.dsn_envelope_id.as_deref(), Some("QQ+314159"));
+    }
+
+    #[test]
+    fn parse_mail_other() {
+        let parsed =
+            parse_mail_parameters(" SIZE=1000 body=8BITMIME SMTPUTF8 AUTH=<>")
+                .expect("invalid parameters");
+        assert_eq!(
+            parsed.other,
+            [
+                ("SIZE".to_string(), Some("1000".to_string())),
+                ("BODY".to_string(), Some("8BITMIME".to_string())),
+                ("SMTPUTF8".to_string(), None),
+                ("AUTH".to_string(), Some("<>".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_mail_invalid() {
+        assert!(parse_mail_parameters(" RET=BODY").is_none());
+        assert!(parse_mail_parameters(" RET").is_none());
+        assert!(parse_mail_parameters(" RET=FULL RET=HDRS").is_none());
+        assert!(parse_mail_parameters(" =1000").is_none());
+    }
+
+    #[test]
+    fn parse_rcpt_dsn() {
+        let parsed = parse_rcpt_parameters(
+            "user@example.com".to_string(),
+            " NOTIFY=FAILURE,delay ORCPT=rfc822;user+2Bx@example.com",
+        )
+        .expect("invalid parameters");
+        assert_eq!(
+            parsed.notify,
+            Some(vec![DsnNotify::Failure, DsnNotify::Delay])
+        );
+        assert_eq!(
+            parsed.original_recipient.as_deref(),
+            Some("rfc822;user+x@example.com")
+        );
+    }
+
+    #[test]
+    fn parse_rcpt_never_exclusive() {
+        assert!(parse_rcpt_parameters(
+            "user@example.com".to_string(),
+            " NOTIFY=NEVER,FAILURE",
+        )
+        .is_none());
+    }
+}
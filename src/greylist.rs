@@ -0,0 +1,73 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    clock::Clock,
+    reply::{EnhancedCode, Reply},
+};
+
+const GREYLISTED: EnhancedCode = EnhancedCode::new(4, 7, 1);
+
+/// The greylisting policy of a server.
+///
+/// The first delivery attempt for each combination of
+/// client IP address, sender and recipient is refused
+/// with a temporary failure.
+/// Retries are accepted once the delay has passed.
+#[derive(Clone, Debug)]
+pub struct Greylisting {
+    /// How long a client must wait before retrying.
+    pub delay: Duration,
+    /// The clock the delay is measured with.
+    pub clock: Clock,
+}
+
+impl Greylisting {
+    /// Greylisting with the given delay, measured with the system clock.
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            clock: Clock::System,
+        }
+    }
+}
+
+/// The greylisting policy together with the delivery attempts seen,
+/// shared by all connections.
+#[derive(Clone, Debug)]
+pub(crate) struct Greylist {
+    policy: Greylisting,
+    first_attempts: Arc<Mutex<HashMap<Triple, Duration>>>,
+}
+
+type Triple = (Option<IpAddr>, String, String);
+
+impl Greylist {
+    pub fn new(policy: Greylisting) -> Self {
+        Self {
+            policy,
+            first_attempts: Arc::default(),
+        }
+    }
+
+    /// The reply to refuse the delivery attempt with, if greylisted.
+    pub fn check(
+        &self,
+        client_ip: Option<IpAddr>,
+        sender: &str,
+        recipient: &str,
+    ) -> Option<Reply> {
+        let now = self.policy.clock.now();
+        let triple = (client_ip, sender.to_string(), recipient.to_string());
+        let mut first_attempts =
+            self.first_attempts.lock().expect("greylist poisoned");
+        let first_attempt = *first_attempts.entry(triple).or_insert(now);
+        (now.saturating_sub(first_attempt) < self.policy.delay).then(|| {
+            Reply::new(451, Some(GREYLISTED), "Greylisted, try again later")
+        })
+    }
+}
@@ -0,0 +1,194 @@
+/// The headers of an email.
+///
+/// Headers keep the order and any duplicates they were received with,
+/// and names are compared ignoring ASCII case.
+/// Values are decoded, including any RFC 2047 encoded words
+/// such as `=?UTF-8?B?...?=`; the values as received
+/// are available with [`Headers::get_raw`] and [`Headers::get_all_raw`].
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Headers {
+    headers: Vec<Header>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Header {
+    name: String,
+    value: String,
+    raw: String,
+}
+
+impl Headers {
+    pub(crate) fn parse(headers: &[mailparse::MailHeader]) -> Self {
+        Self {
+            headers: headers
+                .iter()
+                .map(|header| Header {
+                    name: header.get_key(),
+                    value: header.get_value(),
+                    raw: String::from_utf8_lossy(header.get_value_raw())
+                        .trim()
+                        .to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Get the value of the first header with the given name, if any.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    /// Get the value of the first header with the given name
+    /// as received, without decoding, if any.
+    ///
+    /// See [`Headers::get_all_raw`] for all headers with the name.
+    pub fn get_raw(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.raw.as_str())
+    }
+
+    /// Get the values of all headers with the given name
+    /// as received, without decoding, in order.
+    pub fn get_all_raw<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.raw.as_str())
+    }
+
+    /// Get the values of all headers with the given name, in order.
+    pub fn get_all<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a str> + 'a {
+        self.iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    /// Whether there is any header with the given name.
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Iterate over all headers as name and value pairs, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .map(|header| (header.name.as_str(), header.value.as_str()))
+    }
+
+    /// The number of headers, including duplicates.
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    /// Whether there are no headers at all.
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+}
+
+impl FromIterator<(String, String)> for Headers {
+    fn from_iter<T: IntoIterator<Item = (String, String)>>(iter: T) -> Self {
+        Self {
+            headers: iter
+                .into_iter()
+                .map(|(name, value)| Header {
+                    name,
+                    raw: value.clone(),
+                    value,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Box<dyn Iterator<Item = (&'a str, &'a str)> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+/// Parse a list of message IDs, such as in a `References` header,
+/// into the IDs without angle brackets.
+pub(crate) fn parse_message_ids(value: &str) -> Vec<String> {
+    let mut ids = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        let Some(end) = rest[start..].find('>') else {
+            break;
+        };
+        let id = rest[start + 1..start + end].trim();
+        if !id.is_empty() {
+            ids.push(id.to_string());
+        }
+        rest = &rest[start + end + 1..];
+    }
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_message_ids, Headers};
+
+    #[test]
+    fn message_ids() {
+        assert_eq!(
+            parse_message_ids("<a@example.com>\r\n <b@example.com> (comment)"),
+            ["a@example.com", "b@example.com"]
+        );
+        assert_eq!(parse_message_ids("a@example.com"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn lookup() {
+        let headers = [
+            ("Received", "from b"),
+            ("from", "Sender <sender@example.com>"),
+            ("Received", "from a"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<Headers>();
+        assert_eq!(headers.get("From"), Some("Sender <sender@example.com>"));
+        assert_eq!(headers.get("RECEIVED"), Some("from b"));
+        assert_eq!(
+            headers.get_all("received").collect::<Vec<_>>(),
+            ["from b", "from a"]
+        );
+        assert_eq!(headers.get("To"), None);
+        assert_eq!(headers.iter().count(), 3);
+    }
+
+    #[test]
+    fn raw() {
+        let (headers, _) = mailparse::parse_headers(
+            b"Comment: =?UTF-8?Q?caf=C3=A9?=\r\n\
+              Subject: Hello\r\n\
+              Comment: =?UTF-8?B?dGjDqQ==?=\r\n\
+              \r\n",
+        )
+        .unwrap();
+        let headers = Headers::parse(&headers);
+        assert_eq!(
+            headers.get_all("Comment").collect::<Vec<_>>(),
+            ["caf\u{e9}", "th\u{e9}"]
+        );
+        assert_eq!(
+            headers.get_all_raw("comment").collect::<Vec<_>>(),
+            ["=?UTF-8?Q?caf=C3=A9?=", "=?UTF-8?B?dGjDqQ==?="]
+        );
+        assert_eq!(headers.get_raw("Comment"), Some("=?UTF-8?Q?caf=C3=A9?="));
+    }
+}
@@ -0,0 +1,527 @@
+/// A piece of an HTML document.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) enum Token<'a> {
+    /// Text between tags, with character references decoded.
+    Text(String),
+    /// A start tag, with its lowercase name
+    /// and attributes with lowercase names and decoded values.
+    Start {
+        name: String,
+        attributes: Vec<(String, String)>,
+    },
+    /// An end tag, with its lowercase name.
+    End { name: String },
+    /// The unparsed content of a `script` or `style` element.
+    Raw(&'a str),
+}
+
+impl Token<'_> {
+    /// The value of an attribute of a start tag, if present.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        match self {
+            Self::Start { attributes, .. } => attributes
+                .iter()
+                .find(|(attribute, _)| attribute == name)
+                .map(|(_, value)| value.as_str()),
+            _ => None,
+        }
+    }
+}
+
+/// Split an HTML document into tokens, skipping comments and doctypes.
+///
+/// This is a lenient tokenizer for the HTML found in emails,
+/// which does not build a document tree.
+pub(crate) fn tokenize(html: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = html;
+    while !rest.is_empty() {
+        let Some(start) = rest.find('<') else {
+            tokens.push(Token::Text(decode_entities(rest)));
+            break;
+        };
+        if start > 0 {
+            tokens.push(Token::Text(decode_entities(&rest[..start])));
+        }
+        rest = &rest[start..];
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        if rest.starts_with("<!") || rest.starts_with("<?") {
+            rest = rest.find('>').map_or("", |end| &rest[end + 1..]);
+            continue;
+        }
+        let Some((token, remaining)) = parse_tag(rest) else {
+            // not a tag, such as a lone "<" in text
+            tokens.push(Token::Text("<".to_string()));
+            rest = &rest[1..];
+            continue;
+        };
+        rest = remaining;
+        if let Token::Start { name, .. } = &token {
+            if name == "script" || name == "style" {
+                let end_tag = format!("</{name}");
+                let end =
+                    find_ignore_case(rest, &end_tag).unwrap_or(rest.len());
+                let raw = &rest[..end];
+                tokens.push(token);
+                tokens.push(Token::Raw(raw));
+                rest = &rest[end..];
+                continue;
+            }
+        }
+        tokens.push(token);
+    }
+    tokens
+}
+
+/// Render an HTML document as readable plain text.
+///
+/// Block elements are separated by blank lines, list items are
+/// prefixed with `-` or their number, and images are replaced
+/// by their alt text.
+/// If `urls` is set, the URL of each link follows its text
+/// in parentheses, unless the text is the URL itself.
+pub(crate) fn render_text(html: &str, urls: bool) -> String {
+    let mut renderer = Renderer {
+        urls,
+        ..Renderer::default()
+    };
+    for token in tokenize(html) {
+        renderer.token(token);
+    }
+    renderer.finish()
+}
+
+#[derive(Default)]
+struct Renderer {
+    urls: bool,
+    text: String,
+    /// The number of line breaks to add before the next text.
+    breaks: usize,
+    /// Whether to add a space before the next text.
+    space: bool,
+    /// The depth of `pre` elements.
+    pre: usize,
+    /// The depth of elements whose content is not rendered.
+    hidden: usize,
+    /// The open lists, with the next number for ordered lists.
+    lists: Vec<Option<usize>>,
+    /// The open link, with the length of the text before it.
+    link: Option<(String, usize)>,
+}
+
+impl Renderer {
+    fn token(&mut self, token: Token<'_>) {
+        match token {
+            Token::Text(content) if self.hidden == 0 => self.write(&content),
+            Token::Start { name, attributes } => {
+                let attribute = |name: &str| {
+                    attributes
+                        .iter()
+                        .find(|(attribute, _)| attribute == name)
+                        .map(|(_, value)| value.as_str())
+                };
+                match name.as_str() {
+                    "head" | "title" | "template" => self.hidden += 1,
+                    "br" => {
+                        self.text.push('\n');
+                        self.space = false;
+                    }
+                    "hr" => {
+                        self.block(1);
+                        self.write("---");
+                        self.block(1);
+                    }
+                    "ul" => self.start_list(None),
+                    "ol" => self.start_list(Some(1)),
+                    "li" => self.list_item(),
+                    "td" | "th" => self.space = true,
+                    "img" => {
+                        if let Some(alt) = attribute("alt") {
+                            self.space = true;
+                            self.write(alt);
+                            self.space = true;
+                        }
+                    }
+                    "a" => {
+                        self.link = attribute("href")
+                            .map(|href| (href.trim().to_string(), 0));
+                        // NOTE: start counting after any pending space
+                        self.flush();
+                        if let Some((_, start)) = &mut self.link {
+                            *start = self.text.len();
+                        }
+                    }
+                    "pre" => {
+                        self.block(2);
+                        self.pre += 1;
+                    }
+                    name => self.block(block_breaks(name)),
+                }
+            }
+            Token::End { name } => match name.as_str() {
+                "head" | "title" | "template" => {
+                    self.hidden = self.hidden.saturating_sub(1);
+                }
+                "ul" | "ol" => {
+                    self.lists.pop();
+                    self.block(if self.lists.is_empty() { 2 } else { 1 });
+                }
+                "a" => self.end_link(),
+                "pre" => {
+                    self.pre = self.pre.saturating_sub(1);
+                    self.block(2);
+                }
+                name => self.block(block_breaks(name)),
+            },
+            _ => {}
+        }
+    }
+
+    /// Write text, collapsing whitespace outside of `pre` elements.
+    fn write(&mut self, content: &str) {
+        if self.pre > 0 {
+            self.flush();
+            self.text.push_str(content);
+            return;
+        }
+        for c in content.chars() {
+            if c.is_whitespace() && c != '\u{a0}' {
+                self.space = true;
+            } else {
+                self.flush();
+                self.text.push(if c == '\u{a0}' { ' ' } else { c });
+            }
+        }
+    }
+
+    /// Add any pending line breaks or space before the next text.
+    fn flush(&mut self) {
+        if self.text.is_empty() || self.text.ends_with('\n') {
+            self.breaks = self.breaks.saturating_sub(
+                self.text.len() - self.text.trim_end_matches('\n').len(),
+            );
+        }
+        if !self.text.is_empty() {
+            for _ in 0..self.breaks {
+                self.text.push('\n');
+            }
+            if self.breaks == 0 && self.space && !self.text.ends_with('\n') {
+                self.text.push(' ');
+            }
+        }
+        self.breaks = 0;
+        self.space = false;
+    }
+
+    /// Start a new block, separated by the given number of line breaks.
+    /// Inline elements, with no line breaks, are ignored.
+    fn block(&mut self, breaks: usize) {
+        if breaks > 0 {
+            self.breaks = self.breaks.max(breaks);
+            self.space = false;
+        }
+    }
+
+    fn start_list(&mut self, number: Option<usize>) {
+        self.block(if self.lists.is_empty() { 2 } else { 1 });
+        self.lists.push(number);
+    }
+
+    fn list_item(&mut self) {
+        self.block(1);
+        let indent = "  ".repeat(self.lists.len().saturating_sub(1));
+        let marker = match self.lists.last_mut() {
+            Some(Some(number)) => {
+                *number += 1;
+                format!("{}.", *number - 1)
+            }
+            _ => "-".to_string(),
+        };
+        self.flush();
+        // NOTE: the first item starts the text without a break
+        self.text.push_str(&format!("{indent}{marker} "));
+    }
+
+    fn end_link(&mut self) {
+        let Some((href, start)) = self.link.take() else {
+            return;
+        };
+        if !self.urls || href.is_empty() || href.starts_with('#') {
+            return;
+        }
+        let text = self.text.get(start..).unwrap_or_default().trim();
+        let shown = href.strip_prefix("mailto:").unwrap_or(&href);
+        if text == shown || text == href {
+            return;
+        }
+        if text.is_empty() {
+            self.space = true;
+            self.write(shown);
+        } else {
+            self.text.push_str(&format!(" ({shown})"));
+        }
+    }
+
+    fn finish(self) -> String {
+        let mut text = String::with_capacity(self.text.len());
+        let mut blank_lines = 0;
+        for line in self.text.trim().lines() {
+            let line = line.trim_end();
+            if line.is_empty() {
+                blank_lines += 1;
+                if blank_lines > 1 {
+                    continue;
+                }
+            } else {
+                blank_lines = 0;
+            }
+            text.push_str(line);
+            text.push('\n');
+        }
+        text.truncate(text.trim_end().len());
+        text
+    }
+}
+
+/// The number of line breaks around an element,
+/// which is 0 for inline elements.
+fn block_breaks(name: &str) -> usize {
+    match name {
+        "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "table"
+        | "blockquote" | "dl" | "figure" | "address" => 2,
+        "div" | "tr" | "section" | "article" | "header" | "footer" | "main"
+        | "nav" | "aside" | "form" | "center" | "dt" | "dd" | "caption"
+        | "fieldset" | "body" | "html" | "tbody" | "thead" | "tfoot" => 1,
+        _ => 0,
+    }
+}
+
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+/// Parse the tag at the start of the input,
+/// returning the token and the input following it.
+fn parse_tag(input: &str) -> Option<(Token<'_>, &str)> {
+    let (end_tag, rest) = match input[1..].strip_prefix('/') {
+        Some(rest) => (true, rest),
+        None => (false, &input[1..]),
+    };
+    let name_len = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == ':'))
+        .unwrap_or(rest.len());
+    if name_len == 0 || !rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let name = rest[..name_len].to_ascii_lowercase();
+    let mut rest = &rest[name_len..];
+    let mut attributes = Vec::new();
+    loop {
+        rest = rest
+            .trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+        if let Some(remaining) = rest.strip_prefix('>') {
+            rest = remaining;
+            break;
+        }
+        if rest.is_empty() {
+            break;
+        }
+        let name_len = rest
+            .find(|c: char| c.is_ascii_whitespace() || "=>/".contains(c))
+            .unwrap_or(rest.len())
+            .max(1);
+        let attribute = rest[..name_len].to_ascii_lowercase();
+        rest = rest[name_len..].trim_start();
+        let value = match rest.strip_prefix('=') {
+            Some(value) => {
+                let value = value.trim_start();
+                let (value, remaining) = match value.chars().next() {
+                    Some(quote @ ('"' | '\'')) => {
+                        let value = &value[1..];
+                        match value.find(quote) {
+                            Some(end) => (&value[..end], &value[end + 1..]),
+                            None => (value, ""),
+                        }
+                    }
+                    _ => {
+                        let end = value
+                            .find(|c: char| c.is_ascii_whitespace() || c == '>')
+                            .unwrap_or(value.len());
+                        (&value[..end], &value[end..])
+                    }
+                };
+                rest = remaining;
+                decode_entities(value)
+            }
+            None => String::new(),
+        };
+        attributes.push((attribute, value));
+    }
+    let token = if end_tag {
+        Token::End { name }
+    } else {
+        Token::Start { name, attributes }
+    };
+    Some((token, rest))
+}
+
+/// Decode character references such as `&amp;` and `&#8364;`.
+///
+/// Unknown references are kept as they are.
+pub(crate) fn decode_entities(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest[1..].find(';').and_then(|end| {
+            let reference = &rest[1..end + 1];
+            decode_entity(reference).map(|c| (c, end + 2))
+        });
+        match decoded {
+            Some((c, len)) => {
+                result.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn decode_entity(reference: &str) -> Option<char> {
+    if let Some(number) = reference.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    Some(match reference {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "euro" => '€',
+        "pound" => '£',
+        "hellip" => '…',
+        "ndash" => '–',
+        "mdash" => '—',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "bull" => '•',
+        "middot" => '·',
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_entities, render_text, tokenize, Token};
+
+    #[test]
+    fn entities() {
+        assert_eq!(
+            decode_entities("a &amp; b &lt;&#62; &#x20AC;5 &unknown; & c"),
+            "a & b <> €5 &unknown; & c"
+        );
+    }
+
+    #[test]
+    fn render() {
+        let html = "<html><head><title>Ignored</title>\
+            <style>p { color: red }</style></head><body>\
+            <h1>Welcome,\n   friend!</h1>\
+            <p>Please <a href=\"https://example.com/confirm\">confirm\
+            </a> your   address.<br>Thanks&nbsp;again</p>\
+            <ul><li>One</li><li>Two<ol><li>Nested</li></ol></li></ul>\
+            <table><tr><td>Total:</td><td>5</td></tr></table>\
+            <p><a href=\"https://example.com\">https://example.com</a>\
+            <img alt=\"Logo\" src=\"logo.png\"></p>\
+            <p>Some <b>bold</b> text</p>\
+            <pre>  keep\n  this</pre></body></html>";
+        assert_eq!(
+            render_text(html, true),
+            "Welcome, friend!\n\
+             \n\
+             Please confirm (https://example.com/confirm) your address.\n\
+             Thanks again\n\
+             \n\
+             - One\n\
+             - Two\n  \
+               1. Nested\n\
+             \n\
+             Total: 5\n\
+             \n\
+             https://example.com Logo\n\
+             \n\
+             Some bold text\n\
+             \n  \
+             keep\n  \
+             this"
+        );
+        assert!(render_text(html, false).contains("Please confirm your"));
+    }
+
+    #[test]
+    fn tokens() {
+        let tokens = tokenize(
+            "<!DOCTYPE html><!-- a comment --><P class=x>Hi &amp; \
+             <A HREF='/a?b=1&amp;c=2' disabled>there</a><br/>\
+             <style>p > a { color: red }</style> 1 < 2",
+        );
+        assert_eq!(
+            tokens,
+            [
+                Token::Start {
+                    name: "p".to_string(),
+                    attributes: vec![("class".to_string(), "x".to_string())],
+                },
+                Token::Text("Hi & ".to_string()),
+                Token::Start {
+                    name: "a".to_string(),
+                    attributes: vec![
+                        ("href".to_string(), "/a?b=1&c=2".to_string()),
+                        ("disabled".to_string(), String::new()),
+                    ],
+                },
+                Token::Text("there".to_string()),
+                Token::End {
+                    name: "a".to_string()
+                },
+                Token::Start {
+                    name: "br".to_string(),
+                    attributes: Vec::new(),
+                },
+                Token::Start {
+                    name: "style".to_string(),
+                    attributes: Vec::new(),
+                },
+                Token::Raw("p > a { color: red }"),
+                Token::End {
+                    name: "style".to_string()
+                },
+                Token::Text(" 1 ".to_string()),
+                Token::Text("<".to_string()),
+                Token::Text(" 2".to_string()),
+            ]
+        );
+    }
+}
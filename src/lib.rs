@@ -53,18 +53,58 @@
 
 #![forbid(unsafe_code)]
 
+mod alternative;
+pub mod blocking;
+mod bounce;
+mod clock;
+mod codes;
 mod config;
+mod directory;
+#[cfg(feature = "dkim")]
+mod dkim;
 mod email;
+mod envelope;
+mod greylist;
+mod headers;
+mod html;
+mod limits;
+mod links;
+mod reply;
+mod rules;
 mod server;
 mod smtp;
+mod spf;
+mod threads;
+mod trace;
+mod transcript;
 
 #[cfg(feature = "lettre")]
 mod build;
+#[cfg(feature = "lettre")]
+mod transport;
 
+pub use alternative::TextMismatch;
+pub use bounce::{Bounce, BounceDelivery, FailedRecipient};
+pub use clock::{Clock, ManualClock};
+pub use codes::CodePattern;
 pub use config::Config;
-pub use email::{ConversionError, Email, ParseError};
+#[cfg(feature = "dkim")]
+pub use dkim::{DkimFailure, DkimKeys, DkimResult, DkimSignature};
+pub use email::{ConversionError, Email, ParseError, Part};
+pub use envelope::{DsnNotify, DsnReturn, Recipient};
+pub use greylist::Greylisting;
+pub use headers::Headers;
+pub use limits::{Limits, RateLimit, RateLimitKey};
+pub use links::{Link, LinkSource};
+pub use reply::{EnhancedCode, Reply};
+pub use rules::{Action, Matcher, Rule};
 pub use server::{Error, Server};
-pub use smtp::{Auth, Error as SmtpError};
+pub use smtp::{Auth, Error as SmtpError, HeloValidator, Protocol};
+pub use spf::{Spf, SpfResult};
+pub use threads::Thread;
+pub use transcript::{Direction, Transcript, TranscriptLine};
 
 #[cfg(feature = "lettre")]
 pub use build::MessageBuilderExt;
+#[cfg(feature = "lettre")]
+pub use transport::{ServerTransport, TransportError};
@@ -0,0 +1,216 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    clock::Clock,
+    reply::{EnhancedCode, Reply},
+};
+
+const TOO_MANY_CONNECTIONS: EnhancedCode = EnhancedCode::new(4, 7, 0);
+const TOO_MANY_MESSAGES: EnhancedCode = EnhancedCode::new(4, 7, 0);
+const TOO_MANY_RECIPIENTS: EnhancedCode = EnhancedCode::new(4, 5, 3);
+const RATE_LIMITED: EnhancedCode = EnhancedCode::new(4, 7, 1);
+
+/// The limits a server enforces on its clients.
+///
+/// All limits are disabled by default.
+#[derive(Clone, Default, Debug)]
+pub struct Limits {
+    /// The maximum number of connections handled at the same time.
+    ///
+    /// Further connections receive a `421` greeting and are closed.
+    pub max_connections: Option<usize>,
+    /// The maximum number of messages sent over a single connection.
+    ///
+    /// Further `MAIL FROM` commands receive a `421` reply
+    /// and the connection is closed.
+    pub max_messages_per_connection: Option<usize>,
+    /// The maximum number of recipients of a single message.
+    ///
+    /// Further `RCPT TO` commands receive a `452` reply.
+    pub max_recipients: Option<usize>,
+    /// The maximum rate at which messages are accepted.
+    ///
+    /// Only messages whose content was accepted are counted,
+    /// not transactions that were reset or aborted.
+    /// Further `MAIL FROM` commands receive a `451` reply.
+    pub rate: Option<RateLimit>,
+}
+
+/// A limit on the number of messages accepted in a time window.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    /// The maximum number of messages in any window.
+    pub messages: usize,
+    /// The length of the window.
+    pub window: Duration,
+    /// Who the limit applies to.
+    pub per: RateLimitKey,
+    /// The clock the window is measured with.
+    pub clock: Clock,
+}
+
+/// Who a [`RateLimit`] applies to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RateLimitKey {
+    /// Each client IP address separately.
+    Client,
+    /// Each authenticated user separately.
+    ///
+    /// Clients that did not authenticate
+    /// are limited per IP address instead.
+    User,
+}
+
+impl RateLimit {
+    /// A limit of the given number of messages per window,
+    /// for each client IP address and measured with the system clock.
+    pub fn new(messages: usize, window: Duration) -> Self {
+        Self {
+            messages,
+            window,
+            per: RateLimitKey::Client,
+            clock: Clock::System,
+        }
+    }
+}
+
+/// The limits together with the connections and messages seen,
+/// shared by all connections.
+#[derive(Clone, Default, Debug)]
+pub(crate) struct Limiter {
+    limits: Limits,
+    connections: Arc<AtomicUsize>,
+    messages: Arc<Mutex<HashMap<Key, VecDeque<Duration>>>>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Key {
+    Client(Option<IpAddr>),
+    User(String),
+}
+
+/// A connection counted towards [`Limits::max_connections`]
+/// until dropped.
+#[derive(Debug)]
+pub(crate) struct ConnectionSlot {
+    connections: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Count a new connection,
+    /// or return the reply to refuse it with if there are too many.
+    pub fn connect(&self) -> Result<ConnectionSlot, Reply> {
+        let connections = self.connections.fetch_add(1, Ordering::SeqCst);
+        let slot = ConnectionSlot {
+            connections: self.connections.clone(),
+        };
+        match self.limits.max_connections {
+            Some(max) if connections >= max => Err(Reply::new(
+                421,
+                Some(TOO_MANY_CONNECTIONS),
+                "Too many connections, try again later",
+            )),
+            _ => Ok(slot),
+        }
+    }
+
+    /// The reply to close the connection with
+    /// if too many messages were sent over it.
+    pub fn check_messages(&self, messages: usize) -> Option<Reply> {
+        let max = self.limits.max_messages_per_connection?;
+        (messages >= max).then(|| {
+            Reply::new(
+                421,
+                Some(TOO_MANY_MESSAGES),
+                "Too many messages, closing connection",
+            )
+        })
+    }
+
+    /// The reply to refuse a recipient with
+    /// if the message already has too many.
+    pub fn check_recipients(&self, recipients: usize) -> Option<Reply> {
+        let max = self.limits.max_recipients?;
+        (recipients >= max).then(|| {
+            Reply::new(452, Some(TOO_MANY_RECIPIENTS), "Too many recipients")
+        })
+    }
+
+    /// The reply to refuse a new message from the given client with
+    /// if the rate is exceeded.
+    pub fn check_rate(
+        &self,
+        client_ip: Option<IpAddr>,
+        user: Option<&str>,
+    ) -> Option<Reply> {
+        let rate = self.limits.rate.as_ref()?;
+        let mut messages = self.messages.lock().expect("limiter poisoned");
+        let times = recent(&mut messages, rate, client_ip, user);
+        (times.len() >= rate.messages).then(|| {
+            Reply::new(
+                451,
+                Some(RATE_LIMITED),
+                "Rate limit exceeded, try again later",
+            )
+        })
+    }
+
+    /// Count a message accepted from the given client
+    /// towards the rate limit.
+    pub fn record_message(
+        &self,
+        client_ip: Option<IpAddr>,
+        user: Option<&str>,
+    ) {
+        let Some(rate) = self.limits.rate.as_ref() else {
+            return;
+        };
+        let mut messages = self.messages.lock().expect("limiter poisoned");
+        recent(&mut messages, rate, client_ip, user)
+            .push_back(rate.clock.now());
+    }
+}
+
+/// The times of the messages accepted from the given client
+/// within the current window.
+fn recent<'a>(
+    messages: &'a mut HashMap<Key, VecDeque<Duration>>,
+    rate: &RateLimit,
+    client_ip: Option<IpAddr>,
+    user: Option<&str>,
+) -> &'a mut VecDeque<Duration> {
+    let key = match (rate.per, user) {
+        (RateLimitKey::User, Some(user)) => Key::User(user.to_string()),
+        _ => Key::Client(client_ip),
+    };
+    let now = rate.clock.now();
+    let times = messages.entry(key).or_default();
+    while times
+        .front()
+        .is_some_and(|time| now.saturating_sub(*time) >= rate.window)
+    {
+        times.pop_front();
+    }
+    times
+}
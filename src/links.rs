@@ -0,0 +1,236 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::html::{tokenize, Token};
+
+/// A link found in an email.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Link {
+    /// The URL, with character references such as `&amp;` decoded.
+    pub url: String,
+    /// The text of the link, with whitespace collapsed,
+    /// if found in the html part.
+    pub text: Option<String>,
+    /// The part the link was found in.
+    pub source: LinkSource,
+}
+
+/// The part of an email a [`Link`] was found in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LinkSource {
+    /// The `href` attribute of an `a` or `area` element in the html part.
+    Html,
+    /// A `http` or `https` URL in the text part.
+    Text,
+}
+
+impl Link {
+    /// The percent-decoded value of the first query parameter
+    /// with the given name, if any.
+    pub fn query(&self, name: &str) -> Option<String> {
+        query_pairs(&self.url)
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| percent_decode(value))
+    }
+
+    /// The final destination of the link.
+    ///
+    /// Tracking links often redirect to a URL
+    /// passed as a percent-encoded query parameter,
+    /// such as `https://click.example.com/?u=https%3A%2F%2Fexample.com`.
+    /// This returns that URL, following nested redirects,
+    /// or the URL itself if it has no such parameter.
+    pub fn target(&self) -> String {
+        let mut url = self.url.clone();
+        // NOTE: limit the depth to guard against pathological URLs
+        for _ in 0..10 {
+            let nested = query_pairs(&url)
+                .map(|(_, value)| percent_decode(value))
+                .find(|value| {
+                    value.starts_with("https://")
+                        || value.starts_with("http://")
+                });
+            match nested {
+                Some(nested) => url = nested,
+                None => break,
+            }
+        }
+        url
+    }
+}
+
+/// Find all links in the html part, followed by those in the text part.
+pub(crate) fn extract(body_html: &str, body_text: &str) -> Vec<Link> {
+    let mut links = extract_html(body_html);
+    links.extend(text_url_regex().find_iter(body_text).map(|url| Link {
+        url: trim_url(url.as_str()).to_string(),
+        text: None,
+        source: LinkSource::Text,
+    }));
+    links
+}
+
+fn extract_html(body_html: &str) -> Vec<Link> {
+    let mut links = Vec::new();
+    // the link currently open and its text so far
+    let mut open: Option<(String, String)> = None;
+    for token in tokenize(body_html) {
+        match &token {
+            Token::Start { name, .. } if name == "a" || name == "area" => {
+                if let Some((url, text)) = open.take() {
+                    links.push(html_link(url, &text));
+                }
+                if let Some(url) = token.attribute("href") {
+                    let url = url.trim().to_string();
+                    if name == "a" {
+                        open = Some((url, String::new()));
+                    } else {
+                        let alt = token.attribute("alt").unwrap_or_default();
+                        links.push(html_link(url, alt));
+                    }
+                }
+            }
+            Token::Start { name, .. } if name == "img" => {
+                // use the alt text of images inside links
+                if let (Some((_, text)), Some(alt)) =
+                    (&mut open, token.attribute("alt"))
+                {
+                    text.push(' ');
+                    text.push_str(alt);
+                }
+            }
+            Token::End { name } if name == "a" => {
+                if let Some((url, text)) = open.take() {
+                    links.push(html_link(url, &text));
+                }
+            }
+            Token::Text(content) => {
+                if let Some((_, text)) = &mut open {
+                    text.push_str(content);
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some((url, text)) = open {
+        links.push(html_link(url, &text));
+    }
+    links
+}
+
+fn html_link(url: String, text: &str) -> Link {
+    Link {
+        url,
+        text: Some(text.split_whitespace().collect::<Vec<_>>().join(" ")),
+        source: LinkSource::Html,
+    }
+}
+
+pub(crate) fn text_url_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| {
+        Regex::new(r#"(?i)https?://[^\s<>"]+"#).expect("invalid url regex")
+    })
+}
+
+/// Remove punctuation following a URL in text,
+/// such as the period ending a sentence.
+fn trim_url(url: &str) -> &str {
+    let mut url = url.trim_end_matches(['.', ',', ';', ':', '!', '?', '\'']);
+    while let Some(trimmed) = url.strip_suffix(')') {
+        if url.matches('(').count() >= url.matches(')').count() {
+            break;
+        }
+        url = trimmed.trim_end_matches(['.', ',', ';', ':', '!', '?', '\'']);
+    }
+    url
+}
+
+/// The raw key and value of each query parameter of a URL.
+fn query_pairs(url: &str) -> impl Iterator<Item = (&str, &str)> {
+    let query = url
+        .split_once('?')
+        .map(|(_, query)| query.split('#').next().unwrap_or_default())
+        .unwrap_or_default();
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+}
+
+/// Decode a percent-encoded query value, where `+` encodes a space.
+fn percent_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, remaining)) = rest.split_first() {
+        rest = remaining;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let decoded = rest
+                    .get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match decoded {
+                    Some(decoded) => {
+                        bytes.push(decoded);
+                        rest = &rest[2..];
+                    }
+                    None => bytes.push(b'%'),
+                }
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract, LinkSource};
+
+    #[test]
+    fn links() {
+        let html = "<p>Hello,</p>\
+            <a class=\"button\" href=\"https://click.example.com/c?\
+            u=https%3A%2F%2Fapp.example.com%2Freset%3Ftoken%3Dabc%26id%3D1\
+            &amp;campaign=42\">\n  Reset your\n  <b>password</b> </a>\
+            <a href='mailto:help@example.com'><img alt=Help src=x.png></a>";
+        let text = "Reset your password at \
+            https://app.example.com/reset?token=abc&id=1.\n\
+            (See https://example.com/faq_(general))";
+        let links = extract(html, text);
+        let urls = links
+            .iter()
+            .map(|link| (link.url.as_str(), link.source))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            [
+                (
+                    "https://click.example.com/c?\
+                     u=https%3A%2F%2Fapp.example.com%2Freset\
+                     %3Ftoken%3Dabc%26id%3D1&campaign=42",
+                    LinkSource::Html
+                ),
+                ("mailto:help@example.com", LinkSource::Html),
+                (
+                    "https://app.example.com/reset?token=abc&id=1",
+                    LinkSource::Text
+                ),
+                ("https://example.com/faq_(general)", LinkSource::Text),
+            ]
+        );
+        assert_eq!(links[0].text.as_deref(), Some("Reset your password"));
+        assert_eq!(links[1].text.as_deref(), Some("Help"));
+        assert_eq!(links[2].text, None);
+        assert_eq!(links[0].query("campaign").as_deref(), Some("42"));
+        assert_eq!(
+            links[0].target(),
+            "https://app.example.com/reset?token=abc&id=1"
+        );
+        assert_eq!(links[0].target(), links[2].target());
+    }
+}
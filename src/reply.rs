@@ -0,0 +1,108 @@
+use std::fmt;
+
+/// An enhanced mail system status code (RFC 3463),
+/// such as `5.1.1`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct EnhancedCode {
+    /// The class; 2 for success, 4 for a temporary
+    /// and 5 for a permanent failure.
+    pub class: u8,
+    pub subject: u16,
+    pub detail: u16,
+}
+
+impl EnhancedCode {
+    pub const fn new(class: u8, subject: u16, detail: u16) -> Self {
+        Self {
+            class,
+            subject,
+            detail,
+        }
+    }
+}
+
+impl fmt::Display for EnhancedCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+/// A reply sent by the server in the SMTP exchange.
+///
+/// The text can contain multiple lines separated by `'\n'`,
+/// which are sent as a multiline reply.
+/// Its display format is the reply as sent,
+/// including the final "\r\n".
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Reply {
+    /// The three digit reply code (RFC 5321).
+    pub code: u16,
+    /// The enhanced status code (RFC 3463)
+    /// sent at the start of every line, if any.
+    pub enhanced: Option<EnhancedCode>,
+    pub text: String,
+}
+
+impl Reply {
+    pub fn new(
+        code: u16,
+        enhanced: Option<EnhancedCode>,
+        text: impl Into<String>,
+    ) -> Self {
+        Self {
+            code,
+            enhanced,
+            text: text.into(),
+        }
+    }
+
+    /// Whether this reply indicates success (2xx) or continuation (3xx).
+    pub fn is_positive(&self) -> bool {
+        self.code < 400
+    }
+
+    /// Whether this reply indicates a temporary failure (4xx).
+    pub fn is_transient(&self) -> bool {
+        (400..500).contains(&self.code)
+    }
+
+    /// Whether this reply indicates a permanent failure (5xx).
+    pub fn is_permanent(&self) -> bool {
+        self.code >= 500
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines = self.text.split('\n').peekable();
+        while let Some(line) = lines.next() {
+            let separator = if lines.peek().is_some() { '-' } else { ' ' };
+            write!(f, "{}{separator}", self.code)?;
+            if let Some(enhanced) = self.enhanced {
+                write!(f, "{enhanced} ")?;
+            }
+            write!(f, "{line}\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EnhancedCode, Reply};
+
+    #[test]
+    fn format_single_line() {
+        let reply = Reply::new(550, Some(EnhancedCode::new(5, 1, 1)), "Nope");
+        assert_eq!(reply.to_string(), "550 5.1.1 Nope\r\n");
+    }
+
+    #[test]
+    fn format_multi_line() {
+        let reply = Reply::new(250, None, "example.com\nDSN\nCHUNKING");
+        assert_eq!(
+            reply.to_string(),
+            "250-example.com\r\n250-DSN\r\n250 CHUNKING\r\n"
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use crate::reply::Reply;
+
+/// A rule deciding whether to accept an envelope sender or recipient.
+///
+/// Rules are checked in the order they were added to the server,
+/// and the first matching rule decides.
+/// Addresses not matched by any rule are accepted.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    target: Target,
+    matcher: Matcher,
+    action: Action,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Target {
+    Sender,
+    Recipient,
+}
+
+/// How a [`Rule`] matches an email address.
+#[derive(Clone, Debug)]
+pub enum Matcher {
+    /// Match the address exactly, ignoring ASCII case.
+    Exact(String),
+    /// Match a glob pattern, ignoring ASCII case,
+    /// where `*` matches any sequence of characters
+    /// and `?` matches a single character.
+    ///
+    /// For example `*@blocked.example`.
+    Glob(String),
+    /// Match a regular expression.
+    ///
+    /// The expression is not anchored,
+    /// use `^` and `$` to match the complete address.
+    Regex(regex::Regex),
+}
+
+/// What to do with an address matched by a [`Rule`].
+#[derive(Clone, Debug)]
+pub enum Action {
+    /// Accept the address.
+    Accept,
+    /// Refuse the address with the given reply,
+    /// which should be a temporary (4xx) or permanent (5xx) failure.
+    Reply(Reply),
+}
+
+impl Rule {
+    /// A rule checked at `MAIL FROM` against the envelope sender.
+    ///
+    /// If the sender is refused, the client may try another sender.
+    pub fn sender(matcher: Matcher, action: Action) -> Self {
+        Self {
+            target: Target::Sender,
+            matcher,
+            action,
+        }
+    }
+
+    /// A rule checked at `RCPT TO` against each recipient separately.
+    pub fn recipient(matcher: Matcher, action: Action) -> Self {
+        Self {
+            target: Target::Recipient,
+            matcher,
+            action,
+        }
+    }
+}
+
+impl Matcher {
+    /// Match a glob pattern, see [`Matcher::Glob`].
+    pub fn glob(pattern: impl Into<String>) -> Self {
+        Self::Glob(pattern.into())
+    }
+
+    /// Match a regular expression, see [`Matcher::Regex`].
+    pub fn regex(pattern: &str) -> Result<Self, regex::Error> {
+        regex::Regex::new(pattern).map(Self::Regex)
+    }
+
+    fn matches(&self, address: &str) -> bool {
+        match self {
+            Self::Exact(exact) => exact.eq_ignore_ascii_case(address),
+            Self::Glob(pattern) => glob_matches(
+                &pattern.to_ascii_lowercase().chars().collect::<Vec<_>>(),
+                &address.to_ascii_lowercase().chars().collect::<Vec<_>>(),
+            ),
+            Self::Regex(regex) => regex.is_match(address),
+        }
+    }
+}
+
+/// The reply to refuse the envelope sender with, if any.
+pub(crate) fn check_sender(rules: &[Rule], address: &str) -> Option<Reply> {
+    check(rules, Target::Sender, address)
+}
+
+/// The reply to refuse the recipient with, if any.
+pub(crate) fn check_recipient(rules: &[Rule], address: &str) -> Option<Reply> {
+    check(rules, Target::Recipient, address)
+}
+
+fn check(rules: &[Rule], target: Target, address: &str) -> Option<Reply> {
+    let rule = rules
+        .iter()
+        .find(|rule| rule.target == target && rule.matcher.matches(address))?;
+    match &rule.action {
+        Action::Accept => None,
+        Action::Reply(reply) => Some(reply.clone()),
+    }
+}
+
+/// Match a glob pattern, in O(n·m) time by backtracking
+/// only to the position after the last `*`.
+fn glob_matches(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // the position of the last `*` and the text it was matched up to
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => {
+                let Some((star_p, star_t)) = star else {
+                    return false;
+                };
+                // let the last `*` match one more character
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, t));
+            }
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_recipient, check_sender, Action, Matcher, Rule};
+    use crate::reply::Reply;
+
+    #[test]
+    fn exact() {
+        let matcher = Matcher::Exact("recipient@example.com".to_string());
+        assert!(matcher.matches("recipient@example.com"));
+        assert!(matcher.matches("Recipient@EXAMPLE.com"));
+        assert!(!matcher.matches("recipient@example.com.au"));
+    }
+
+    #[test]
+    fn glob() {
+        let matcher = Matcher::glob("*@Blocked.example");
+        assert!(matcher.matches("someone@blocked.example"));
+        assert!(matcher.matches("@BLOCKED.example"));
+        assert!(!matcher.matches("someone@blocked.example.com"));
+        let matcher = Matcher::glob("user?@*.example");
+        assert!(matcher.matches("user1@mail.example"));
+        assert!(!matcher.matches("user@mail.example"));
+        let matcher = Matcher::glob("a*b*c");
+        assert!(matcher.matches("abc"));
+        assert!(matcher.matches("axxbyybc"));
+        assert!(!matcher.matches("axxbyy"));
+    }
+
+    #[test]
+    fn glob_many_stars() {
+        let matcher = Matcher::glob("*a*a*a*a*a*a*a*a*a*a*b");
+        let address = "a".repeat(1000);
+        assert!(!matcher.matches(&address));
+        assert!(matcher.matches(&format!("{address}b")));
+    }
+
+    #[test]
+    fn first_match_decides() {
+        let rules = [
+            Rule::recipient(
+                Matcher::Exact("vip@blocked.example".to_string()),
+                Action::Accept,
+            ),
+            Rule::recipient(
+                Matcher::glob("*@blocked.example"),
+                Action::Reply(Reply::new(550, None, "Blocked")),
+            ),
+            Rule::recipient(
+                Matcher::regex("^slow@").unwrap(),
+                Action::Reply(Reply::new(450, None, "Try again later")),
+            ),
+        ];
+        assert_eq!(check_recipient(&rules, "vip@blocked.example"), None);
+        assert_eq!(
+            check_recipient(&rules, "other@blocked.example")
+                .unwrap()
+                .code,
+            550
+        );
+        assert_eq!(
+            check_recipient(&rules, "slow@example.com").unwrap().code,
+            450
+        );
+        assert_eq!(check_recipient(&rules, "anyone@example.com"), None);
+        assert_eq!(check_sender(&rules, "other@blocked.example"), None);
+    }
+}
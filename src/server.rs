@@ -1,29 +1,82 @@
-use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
-use tokio::sync::mpsc;
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, AsyncWrite, BufReader},
+    sync::mpsc,
+};
 use tokio_stream::Stream;
 
-use crate::{smtp::Response, Auth, Email};
+use crate::{
+    directory::Directory,
+    greylist::Greylist,
+    limits::Limiter,
+    smtp::{HeloValidator, Protocol, Response, Session, Settings},
+    threads::{self, Thread},
+    transcript::{Recorded, Recorder, Transcripts},
+    Action, Auth, Bounce, BounceDelivery, Email, EnhancedCode, Greylisting,
+    Limits, Matcher, Reply, Rule, Spf, Transcript,
+};
 
 pub const DEFAULT_PORT: u16 = 587;
 
 /// An error while receiving email.
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error(transparent)]
-    Smtp(#[from] crate::smtp::Error),
-    #[error(transparent)]
-    Parse(#[from] crate::email::ParseError),
+    /// An error in a connection, with the transcript of that connection.
+    #[error("{0}")]
+    Smtp(#[source] crate::smtp::Error, Transcript),
+    /// An error parsing a received email,
+    /// with the transcript of the connection it was received over.
+    #[error("{0}")]
+    Parse(#[source] crate::email::ParseError, Transcript),
     #[error(transparent)]
     Accept(#[from] std::io::Error),
 }
 
+impl Error {
+    /// The transcript of the connection this error occurred in, if any.
+    pub fn transcript(&self) -> Option<&Transcript> {
+        match self {
+            Self::Smtp(_, transcript) | Self::Parse(_, transcript) => {
+                Some(transcript)
+            }
+            Self::Accept(_) => None,
+        }
+    }
+}
+
+/// The socket a [`Server`] accepts connections on.
+enum Listener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, path) = self {
+            // NOTE: the socket file is not removed when the listener closes,
+            //       and would prevent binding the same path again
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
 /// An SMTP email server.
 pub struct Server {
-    auth: Auth,
-    listener: tokio::net::TcpListener,
+    settings: Settings,
+    listener: Listener,
     channel_tx: mpsc::Sender<Result<Email, Error>>,
     channel_rx: mpsc::Receiver<Result<Email, Error>>,
+    bounce_rx: mpsc::UnboundedReceiver<Bounce>,
+    received: Vec<Email>,
 }
 
 impl Server {
@@ -34,13 +87,62 @@ impl Server {
     ) -> Result<Self, std::io::Error> {
         use tokio::net::TcpListener;
         let listener = TcpListener::bind(address).await?;
+        Ok(Self::new(Listener::Tcp(listener), auth))
+    }
+
+    /// Start a new server instance
+    /// listening on a Unix domain socket at the given path.
+    ///
+    /// The socket file must not exist yet,
+    /// and is removed again when the server is dropped.
+    /// Without an IP address to identify itself with,
+    /// the server uses `localhost` as its hostname,
+    /// unless set with [`Server::set_hostname`].
+    #[cfg(unix)]
+    pub async fn start_unix(
+        path: impl AsRef<Path>,
+        auth: Auth,
+    ) -> Result<Self, std::io::Error> {
+        use tokio::net::UnixListener;
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path)?;
+        Ok(Self::new(Listener::Unix(listener, path), auth))
+    }
+
+    fn new(listener: Listener, auth: Auth) -> Self {
         let (channel_tx, channel_rx) = mpsc::channel(1);
-        Ok(Self {
-            auth,
+        let (bounce_tx, bounce_rx) = mpsc::unbounded_channel();
+        Self {
+            settings: Settings {
+                auth,
+                protocol: Protocol::Smtp,
+                hostname: None,
+                banner: None,
+                capabilities: None,
+                helo_validator: None,
+                directory: Directory::default(),
+                chunking: false,
+                rules: Vec::new(),
+                spf: None,
+                greylist: None,
+                limiter: Limiter::default(),
+                transcripts: Transcripts::default(),
+                mask_secrets: true,
+                delivery_failures: Vec::new(),
+                bounces: None,
+                bounce_return_path: None,
+                bounce_channel: bounce_tx,
+                trace_headers: false,
+                sender_ips: HashMap::new(),
+                #[cfg(feature = "dkim")]
+                dkim_keys: crate::DkimKeys::default(),
+            },
             listener,
             channel_tx,
             channel_rx,
-        })
+            received: Vec::new(),
+            bounce_rx,
+        }
     }
 
     /// Start a new server instance
@@ -72,9 +174,342 @@ impl Server {
         Self::start(address, auth).await
     }
 
+    /// Set the protocol spoken by the server.
+    ///
+    /// In [`Protocol::Lmtp`] mode, clients greet with `LHLO`
+    /// and the server replies once per recipient
+    /// after the message content, see [`Server::fail_delivery`].
+    /// The default is [`Protocol::Smtp`].
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.settings.protocol = protocol;
+    }
+
+    /// Fail delivery to the recipient with the given email address
+    /// with the given reply, in LMTP mode.
+    ///
+    /// The address is compared ignoring ASCII case,
+    /// like [`Matcher::Exact`].
+    ///
+    /// The recipient is accepted at `RCPT TO`,
+    /// but receives this reply after the message content
+    /// and is not included in the received email.
+    /// If delivery fails for all recipients,
+    /// no email is received at all.
+    pub fn fail_delivery(&mut self, address: impl Into<String>, reply: Reply) {
+        self.settings
+            .delivery_failures
+            .push((address.into(), reply));
+    }
+
+    /// Set the hostname the server identifies itself with
+    /// in the greeting and `EHLO` reply,
+    /// or `None` to use the address it is bound to.
+    pub fn set_hostname(&mut self, hostname: Option<String>) {
+        self.settings.hostname = hostname;
+    }
+
+    /// Set the text sent after the hostname in the greeting,
+    /// for example `"ESMTP Postfix (Ubuntu)"`.
+    pub fn set_banner(&mut self, banner: Option<String>) {
+        self.settings.banner = banner;
+    }
+
+    /// Set exactly which capabilities are advertised in the `EHLO` reply,
+    /// for example `"SIZE 35882577"` or `"8BITMIME"`,
+    /// or `None` to advertise the extensions supported by the server.
+    ///
+    /// This only changes the advertisement;
+    /// unknown capabilities are advertised as given
+    /// and supported extensions remain enabled or disabled
+    /// regardless of whether they are listed.
+    pub fn set_capabilities(&mut self, capabilities: Option<Vec<String>>) {
+        self.settings.capabilities = capabilities;
+    }
+
+    /// Set a function that checks the name given by clients
+    /// in the `EHLO` or `HELO` command,
+    /// or `None` to accept any name.
+    ///
+    /// Clients giving a name for which the function returns `false`
+    /// receive a `501` reply and may try again.
+    pub fn set_helo_validator(&mut self, validator: Option<HeloValidator>) {
+        self.settings.helo_validator = validator;
+    }
+
+    /// Add a user to answer `VRFY` commands with.
+    ///
+    /// Clients can verify the user by its email address,
+    /// the local part of its address or its full name.
+    /// Without any users, the server answers all `VRFY` commands
+    /// with `252`, meaning it cannot verify the user.
+    pub fn add_user(
+        &mut self,
+        address: impl Into<String>,
+        name: Option<String>,
+    ) {
+        self.settings.directory.add_user(address.into(), name);
+    }
+
+    /// Add a mailing list to answer `EXPN` commands with.
+    ///
+    /// Without any mailing lists, the server answers
+    /// all `EXPN` commands with `502`, meaning it is not available.
+    pub fn add_mailing_list(
+        &mut self,
+        name: impl Into<String>,
+        members: Vec<String>,
+    ) {
+        self.settings
+            .directory
+            .add_mailing_list(name.into(), members);
+    }
+
+    /// Enable or disable the `CHUNKING` extension (RFC 3030).
+    ///
+    /// When enabled, the server advertises `CHUNKING`
+    /// and clients may send the message content
+    /// using `BDAT` commands instead of `DATA`.
+    /// This is disabled by default.
+    pub fn set_chunking(&mut self, enabled: bool) {
+        self.settings.chunking = enabled;
+    }
+
+    /// Add a rule deciding whether to accept
+    /// an envelope sender or recipient.
+    ///
+    /// Rules are checked in the order they were added,
+    /// and the first matching rule decides.
+    /// Recipients refused with a permanent failure are treated
+    /// like those rejected with [`Server::reject_recipient`].
+    ///
+    /// ```
+    /// # tokio_test::block_on(async {
+    /// use smtp_test_server::{
+    ///     Action, Auth, EnhancedCode, Matcher, Reply, Rule, Server,
+    /// };
+    ///
+    /// let mut server =
+    ///     Server::start("127.0.0.1:0".parse().unwrap(), Auth::AcceptAll)
+    ///         .await
+    ///         .unwrap();
+    /// server.add_rule(Rule::recipient(
+    ///     Matcher::glob("*@blocked.example"),
+    ///     Action::Reply(Reply::new(
+    ///         550,
+    ///         Some(EnhancedCode::new(5, 7, 1)),
+    ///         "Delivery not authorized",
+    ///     )),
+    /// ));
+    /// server.add_rule(Rule::recipient(
+    ///     Matcher::regex("^slow@").unwrap(),
+    ///     Action::Reply(Reply::new(
+    ///         450,
+    ///         Some(EnhancedCode::new(4, 2, 1)),
+    ///         "Try again later",
+    ///     )),
+    /// ));
+    /// # })
+    /// ```
+    pub fn add_rule(&mut self, rule: Rule) {
+        self.settings.rules.push(rule);
+    }
+
+    /// Set whether to mask the credentials sent with `AUTH`
+    /// in transcripts, which is enabled by default.
+    pub fn set_mask_secrets(&mut self, enabled: bool) {
+        self.settings.mask_secrets = enabled;
+    }
+
+    /// Take a copy of the transcripts of all connections
+    /// accepted so far, in order, including those still open.
+    ///
+    /// Transcripts are also attached to each received [`Email`]
+    /// and to errors in connections, see [`Error::transcript`].
+    pub fn transcripts(&self) -> Vec<Transcript> {
+        self.settings.transcripts.all()
+    }
+
+    /// Set the limits enforced on clients.
+    ///
+    /// This resets the connections and messages counted so far.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.settings.limiter = Limiter::new(limits);
+    }
+
+    /// Enable SPF checks with the given policy, or `None` to disable them.
+    ///
+    /// The result is recorded in [`Email::spf`].
+    /// Connections without an IP address,
+    /// such as over a Unix domain socket,
+    /// result in [`crate::SpfResult::None`].
+    /// This is disabled by default.
+    pub fn set_spf(&mut self, policy: Option<Spf>) {
+        self.settings.spf = policy;
+    }
+
+    /// Enable greylisting with the given policy, or `None` to disable it.
+    ///
+    /// Recipients not refused by any rule are greylisted
+    /// on the first delivery attempt from a client IP address and sender,
+    /// with a `451 4.7.1` reply.
+    /// Connections without an IP address, such as over a Unix domain socket,
+    /// share a single entry.
+    /// This is disabled by default.
+    pub fn set_greylisting(&mut self, policy: Option<Greylisting>) {
+        self.settings.greylist = policy.map(Greylist::new);
+    }
+
+    /// Set the public keys to verify DKIM signatures with,
+    /// see [`Email::dkim`].
+    ///
+    /// Signatures for keys not in the table fail verification,
+    /// as no DNS lookups are made.
+    /// This method is only available with the `dkim` feature.
+    #[cfg(feature = "dkim")]
+    pub fn set_dkim_keys(&mut self, keys: crate::DkimKeys) {
+        self.settings.dkim_keys = keys;
+    }
+
+    /// Add `Received` and `Authentication-Results` headers
+    /// to the top of each received email, as a receiving MTA would.
+    ///
+    /// The `Authentication-Results` header reports the result of `AUTH`,
+    /// the SPF check if enabled with [`Server::set_spf`],
+    /// or otherwise the check of the client against the addresses allowed
+    /// with [`Server::allow_sender_ip`],
+    /// and with the `dkim` feature, the result of each DKIM signature.
+    /// ARC headers (RFC 8617) are not added,
+    /// as the server has no key to seal messages with.
+    /// This is disabled by default.
+    pub fn set_trace_headers(&mut self, enabled: bool) {
+        self.settings.trace_headers = enabled;
+    }
+
+    /// Allow the client IP address to send email for the sender domain,
+    /// which is reported as `spf=pass` in the `Authentication-Results`
+    /// header, see [`Server::set_trace_headers`].
+    ///
+    /// Senders from domains with allowed addresses
+    /// are reported as `spf=fail` if sent from another address,
+    /// senders from other domains as `spf=none`.
+    pub fn allow_sender_ip(&mut self, domain: impl Into<String>, ip: IpAddr) {
+        self.settings
+            .sender_ips
+            .entry(domain.into().to_ascii_lowercase())
+            .or_default()
+            .push(ip);
+    }
+
+    /// Reject the recipient with the given email address.
+    ///
+    /// By default, the recipient is refused at `RCPT TO`.
+    /// If bounces are enabled with [`Server::set_bounces`],
+    /// the recipient is accepted instead and a bounce is generated
+    /// once the message has been received.
+    pub fn reject_recipient(&mut self, address: impl Into<String>) {
+        self.add_rule(Rule::recipient(
+            Matcher::Exact(address.into()),
+            Action::Reply(Reply::new(
+                550,
+                Some(EnhancedCode::new(5, 1, 1)),
+                "Mailbox unavailable",
+            )),
+        ));
+    }
+
+    /// Set where to deliver bounces for rejected recipients,
+    /// or `None` to refuse them during the SMTP exchange instead.
+    ///
+    /// Only recipients rejected with a permanent failure are bounced;
+    /// temporary failures are always sent during the SMTP exchange.
+    ///
+    /// The message is not delivered to bounced recipients,
+    /// and only the bounce is generated if all recipients were bounced.
+    ///
+    /// Bounces are `multipart/report` delivery status notifications
+    /// (RFC 3464) and respect the `NOTIFY` and `RET` parameters
+    /// sent by the client.
+    /// This is disabled by default.
+    pub fn set_bounces(&mut self, delivery: Option<BounceDelivery>) {
+        self.settings.bounces = delivery;
+    }
+
+    /// Set the address to send bounces to,
+    /// or `None` to use the envelope sender of the original message.
+    pub fn set_bounce_return_path(&mut self, address: Option<String>) {
+        self.settings.bounce_return_path = address;
+    }
+
+    /// Take all bounces generated so far
+    /// with [`BounceDelivery::Mailbox`].
+    ///
+    /// The bounce for a message is available
+    /// before the message itself is received.
+    pub fn bounces(&mut self) -> Vec<Bounce> {
+        let mut bounces = Vec::new();
+        while let Ok(bounce) = self.bounce_rx.try_recv() {
+            bounces.push(bounce);
+        }
+        bounces
+    }
+
+    /// All emails received so far, in the order they were received.
+    ///
+    /// This includes emails already returned by [`Server::try_receive`]
+    /// and the other receiving methods.
+    pub fn received(&self) -> &[Email] {
+        &self.received
+    }
+
+    /// Group all emails received so far into conversation threads,
+    /// using their `Message-ID`, `In-Reply-To` and `References` headers.
+    ///
+    /// Threads are ordered by their first email.
+    pub fn threads(&self) -> Vec<Thread> {
+        threads::threads(&self.received)
+    }
+
     /// Return the address and port to which this server bound.
+    ///
+    /// Returns an error for servers listening on a Unix domain socket,
+    /// see [`Server::path`].
     pub fn address(&self) -> Result<SocketAddr, std::io::Error> {
-        self.listener.local_addr()
+        match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr(),
+            #[cfg(unix)]
+            Listener::Unix(..) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "server listens on a unix domain socket",
+            )),
+        }
+    }
+
+    /// Return the path of the Unix domain socket
+    /// this server listens on, if any.
+    #[cfg(unix)]
+    pub fn path(&self) -> Option<&Path> {
+        match &self.listener {
+            Listener::Tcp(_) => None,
+            Listener::Unix(_, path) => Some(path),
+        }
+    }
+
+    /// Create a [`lettre`] transport that delivers emails
+    /// straight into this server, without a network connection.
+    ///
+    /// The transport uses the configuration of the server
+    /// at the time it is created,
+    /// so configure the server before calling this method.
+    /// This must be called from within a tokio runtime.
+    /// This method is only available with the `lettre` feature.
+    #[cfg(feature = "lettre")]
+    pub fn transport(&self) -> crate::ServerTransport {
+        let ip = self.address().ok().map(|address| address.ip());
+        crate::ServerTransport::new(
+            self.settings.clone(),
+            self.settings.hostname(ip.as_ref()),
+            self.channel_tx.clone(),
+        )
     }
 
     /// Create a stream of emails.
@@ -120,48 +555,148 @@ impl Server {
         }
     }
 
+    /// Receive the next email matching the predicate,
+    /// waiting at most for the given duration.
+    ///
+    /// Returns `None` if no matching email was received in time.
+    /// Other emails are skipped, but remain in [`Server::received`].
+    /// This method discards any errors that occur.
+    pub async fn receive_matching<F>(
+        &mut self,
+        timeout: Duration,
+        mut predicate: F,
+    ) -> Option<Email>
+    where
+        F: FnMut(&Email) -> bool,
+    {
+        let receive = async {
+            loop {
+                let email = self.receive().await;
+                if predicate(&email) {
+                    return email;
+                }
+            }
+        };
+        tokio::time::timeout(timeout, receive).await.ok()
+    }
+
+    /// Receive the next email for the given recipient,
+    /// waiting at most for the given duration,
+    /// see [`Server::receive_matching`].
+    ///
+    /// For example, to receive a one-time code:
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # async fn example(mut server: smtp_test_server::Server) {
+    /// let code = server
+    ///     .receive_to("new-user@example.com", Duration::from_secs(5))
+    ///     .await
+    ///     .and_then(|email| email.code());
+    /// # }
+    /// ```
+    pub async fn receive_to(
+        &mut self,
+        address: &str,
+        timeout: Duration,
+    ) -> Option<Email> {
+        self.receive_matching(timeout, |email| {
+            email.recipient(address).is_some()
+        })
+        .await
+    }
+
     /// Try to receive a single email.
     pub async fn try_receive(&mut self) -> Result<Email, Error> {
         loop {
             tokio::select! {
-                result = self.listener.accept() => match result {
-                    Ok((socket, client_address)) => {
-                        tokio::spawn(task(
-                            socket, self.address()?.ip(), client_address.ip(), self.auth.clone(), self.channel_tx.clone())
-                        );
-                    }
+                result = self.listener.accept(
+                    &self.settings, &self.channel_tx,
+                ) => match result {
+                    Ok(()) => {}
                     Err(e) => return Err(Error::Accept(e))
                 },
                 email_result = self.channel_rx.recv() => {
                     // NOTE: since the server keeps a sender itself,
                     //       the channel never closes and
                     //       we cannot receive `None`
-                    return email_result.expect("senders closed")
+                    let email_result = email_result.expect("senders closed");
+                    if let Ok(email) = &email_result {
+                        self.received.push(email.clone());
+                    }
+                    return email_result;
                 }
             }
         }
     }
 }
 
-async fn task(
-    mut socket: tokio::net::TcpStream,
-    client_ip: IpAddr,
-    server_ip: IpAddr,
-    auth: Auth,
+impl Listener {
+    /// Accept a single connection and spawn a task to handle it.
+    async fn accept(
+        &self,
+        settings: &Settings,
+        channel: &mpsc::Sender<Result<Email, Error>>,
+    ) -> Result<(), std::io::Error> {
+        match self {
+            Self::Tcp(listener) => {
+                let (socket, client) = listener.accept().await?;
+                let ip = listener.local_addr()?.ip();
+                let hostname = settings.hostname(Some(&ip));
+                tokio::spawn(task(
+                    socket,
+                    hostname,
+                    Some(client.ip()),
+                    settings.clone(),
+                    channel.clone(),
+                ));
+            }
+            #[cfg(unix)]
+            Self::Unix(listener, _) => {
+                let (socket, _) = listener.accept().await?;
+                let hostname = settings.hostname(None);
+                tokio::spawn(task(
+                    socket,
+                    hostname,
+                    None,
+                    settings.clone(),
+                    channel.clone(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) async fn task<S>(
+    socket: S,
+    hostname: String,
+    client_ip: Option<IpAddr>,
+    settings: Settings,
     channel: mpsc::Sender<Result<Email, Error>>,
-) {
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let recorder = settings.transcripts.start(client_ip, settings.mask_secrets);
+    let mut socket = BufReader::new(Recorded::new(socket, recorder.clone()));
+    let mut session = Session::new(hostname.clone(), client_ip);
     loop {
-        let result = run(&mut socket, &server_ip, &client_ip, &auth).await;
+        let result =
+            run(&mut socket, &hostname, &mut session, &settings, &recorder)
+                .await;
         let result = match result {
-            Ok(Response::Email(email)) => channel.send(Ok(email)).await,
-            Ok(Response::Continue) => Ok(()),
+            Ok(Response::Email(mut email)) => {
+                email.transcript = recorder.transcript();
+                channel.send(Ok(email)).await
+            }
             Ok(Response::Quit) => return,
-            Err(Error::Smtp(crate::smtp::Error::Io(e)))
-                if e.kind() == std::io::ErrorKind::BrokenPipe =>
+            Err(Error::Smtp(crate::smtp::Error::Io(e), _))
+                if e.kind() == std::io::ErrorKind::BrokenPipe
+                    || e.kind() == std::io::ErrorKind::UnexpectedEof =>
             {
                 return
             }
-            Err(e) => channel.send(Err(e)).await,
+            Err(error) => channel.send(Err(error)).await,
         };
         if result.is_err() {
             // error sending on channel because it has closed
@@ -172,19 +707,37 @@ async fn task(
 }
 
 async fn run(
-    socket: &mut tokio::net::TcpStream,
-    client_ip: &IpAddr,
-    server_ip: &IpAddr,
-    auth: &Auth,
+    socket: &mut (impl AsyncBufRead + AsyncWrite + Unpin),
+    hostname: &str,
+    session: &mut Session,
+    settings: &Settings,
+    recorder: &Recorder,
 ) -> Result<Response<Email>, Error> {
-    let response =
-        crate::smtp::receive(socket, server_ip, client_ip, auth).await?;
+    let response = crate::smtp::receive(socket, settings, session)
+        .await
+        .map_err(|e| Error::Smtp(e, recorder.transcript()))?;
     match response {
-        Response::Email(data) => {
-            let email = Email::parse(data)?;
+        Response::Email(mut data) => {
+            #[cfg(feature = "dkim")]
+            let dkim = crate::dkim::verify(&data.email, &settings.dkim_keys);
+            if settings.trace_headers {
+                crate::trace::prepend(
+                    &mut data,
+                    settings,
+                    hostname,
+                    #[cfg(feature = "dkim")]
+                    &dkim,
+                );
+            }
+            #[allow(unused_mut)]
+            let mut email = Email::parse(data)
+                .map_err(|e| Error::Parse(e, recorder.transcript()))?;
+            #[cfg(feature = "dkim")]
+            {
+                email.dkim = dkim;
+            }
             Ok(Response::Email(email))
         }
-        Response::Continue => Ok(Response::Continue),
         Response::Quit => Ok(Response::Quit),
     }
 }
@@ -196,6 +749,12 @@ mod tests {
     use lettre::transport::smtp::{
         authentication::Credentials, AsyncSmtpTransportBuilder,
     };
+    use tokio::{
+        io::{
+            AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader,
+        },
+        net::TcpStream,
+    };
 
     use super::{Auth, Server};
     use crate::MessageBuilderExt;
@@ -223,7 +782,7 @@ mod tests {
     {
         tokio::time::timeout(TIMEOUT, future)
             .await
-            .expect(&format!("timeout {op}"))
+            .unwrap_or_else(|_| panic!("timeout {op}"))
     }
 
     async fn expect_timeout<F>(op: &str, future: F)
@@ -242,16 +801,54 @@ mod tests {
             .expect("error testing client");
     }
 
-    async fn send(
-        client: &mut SmtpClient,
+    async fn connect_raw(address: SocketAddr) -> BufReader<TcpStream> {
+        let socket = timeout("connecting", TcpStream::connect(address))
+            .await
+            .expect("error connecting");
+        let mut socket = BufReader::new(socket);
+        let greeting = read_reply(&mut socket).await;
+        assert!(greeting.starts_with("220 "), "greeting: {greeting:?}");
+        socket
+    }
+
+    async fn read_reply<S>(socket: &mut BufReader<S>) -> String
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            timeout("reading reply", socket.read_line(&mut line))
+                .await
+                .expect("error reading reply");
+            reply.push_str(&line);
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return reply;
+            }
+        }
+    }
+
+    async fn command_raw<S>(socket: &mut BufReader<S>, data: &[u8]) -> String
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        socket
+            .get_mut()
+            .write_all(data)
+            .await
+            .expect("error writing command");
+        read_reply(socket).await
+    }
+
+    fn build_message(
         addr_from: (&str, &str),
         addr_to: (&str, &str),
         subject: &str,
         body_text: &str,
         body_html: &str,
-    ) {
-        use lettre::{message::Mailbox, AsyncTransport, Message};
-        let message = Message::builder()
+    ) -> lettre::Message {
+        use lettre::{message::Mailbox, Message};
+        Message::builder()
             .from(Mailbox::new(
                 Some(addr_from.0.to_string()),
                 addr_from.1.parse().unwrap(),
@@ -262,7 +859,20 @@ mod tests {
             ))
             .subject(subject)
             .body_text_and_html(body_text.to_string(), body_html.to_string())
-            .expect("invalid email message");
+            .expect("invalid email message")
+    }
+
+    async fn send(
+        client: &mut SmtpClient,
+        addr_from: (&str, &str),
+        addr_to: (&str, &str),
+        subject: &str,
+        body_text: &str,
+        body_html: &str,
+    ) {
+        use lettre::AsyncTransport;
+        let message =
+            build_message(addr_from, addr_to, subject, body_text, body_html);
         let response = timeout("sending email", client.send(message))
             .await
             .expect("error sending email message");
@@ -289,15 +899,16 @@ mod tests {
                 let email = timeout("receiving email", server.try_receive())
                     .await
                     .expect("error receiving email");
+                assert_eq!(&email.helo_name, "[127.0.0.1]");
                 assert_eq!(&email.address_from, "sender@example.com");
                 assert_eq!(&email.address_to, "recipient@example.com");
                 assert_eq!(
                     email.get_from(),
-                    format!("Sender <sender@example.com>")
+                    Some("Sender <sender@example.com>")
                 );
                 assert_eq!(
                     email.get_to(),
-                    format!("Recipient <recipient@example.com>")
+                    Some("Recipient <recipient@example.com>")
                 );
                 assert_eq!(&email.subject, "Hello world");
                 assert_eq!(&email.body_text, "Welcome\r\n");
@@ -389,7 +1000,7 @@ mod tests {
                     "{}",
                     error.source().expect("missing error source")
                 );
-                assert_eq!(source, "Authentication failed");
+                assert_eq!(source, "5.7.8 Authentication failed");
             }
         }
     }
@@ -412,23 +1023,1768 @@ mod tests {
                     "{}",
                     error.source().expect("missing error source")
                 );
-                assert_eq!(source, "Authentication failed");
+                assert_eq!(source, "5.7.8 Authentication failed");
             }
         }
     }
 
     #[tokio::test]
     async fn test_auth_anon_fail() {
-        let server = start_server(Auth::Login {
+        use lettre::AsyncTransport;
+        let mut server = start_server(Auth::Login {
             username: "user".to_string(),
             password: "pwd".to_string(),
         })
         .await;
         let address = server.address().unwrap();
         let client: SmtpClient = build_client(address).build();
-        match run_test_auth_fail(server, client).await {
-            Ok(is_ok) => assert!(!is_ok),
-            Err(error) => panic!("unexpected error: {error:?}"),
+        let message = build_message(
+            ("Sender", "sender@example.com"),
+            ("Recipient", "recipient@example.com"),
+            "Hello world",
+            "Welcome",
+            "<p>Welcome</p>",
+        );
+        tokio::select! {
+            result = server.try_receive() => {
+                panic!("unexpected result: {result:?}")
+            }
+            result = timeout("sending email", client.send(message)) => {
+                use std::error::Error;
+                let error = result.expect_err("expected auth fail");
+                assert!(error.is_permanent(), "{error:?}");
+                let source = format!(
+                    "{}",
+                    error.source().expect("missing error source")
+                );
+                assert_eq!(source, "5.7.0 Authentication required");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_chunking() {
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        server.set_chunking(true);
+        let address = server.address().unwrap();
+        tokio::join!(
+            async move {
+                let message = build_message(
+                    ("Sender", "sender@example.com"),
+                    ("Recipient", "recipient@example.com"),
+                    "Hello world",
+                    "Welcome",
+                    "<p>Welcome</p>",
+                )
+                .formatted();
+                let (chunk1, chunk2) = message.split_at(message.len() / 2);
+                let mut socket = connect_raw(address).await;
+                let reply =
+                    command_raw(&mut socket, b"EHLO [127.0.0.1]\r\n").await;
+                assert!(reply.contains("250-CHUNKING\r\n"), "{reply:?}");
+                let reply = command_raw(
+                    &mut socket,
+                    b"MAIL FROM:<sender@example.com>\r\n",
+                )
+                .await;
+                assert!(reply.starts_with("250 "), "{reply:?}");
+                let reply = command_raw(
+                    &mut socket,
+                    b"RCPT TO:<recipient@example.com>\r\n",
+                )
+                .await;
+                assert!(reply.starts_with("250 "), "{reply:?}");
+                let mut data =
+                    format!("BDAT {}\r\n", chunk1.len()).into_bytes();
+                data.extend(chunk1);
+                let reply = command_raw(&mut socket, &data).await;
+                assert!(reply.starts_with("250 "), "{reply:?}");
+                let mut data =
+                    format!("BDAT {} LAST\r\n", chunk2.len()).into_bytes();
+                data.extend(chunk2);
+                let reply = command_raw(&mut socket, &data).await;
+                assert!(reply.starts_with("250 "), "{reply:?}");
+                let reply = command_raw(&mut socket, b"QUIT\r\n").await;
+                assert!(reply.starts_with("221 "), "{reply:?}");
+            },
+            async move {
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                assert_eq!(&email.address_from, "sender@example.com");
+                assert_eq!(&email.address_to, "recipient@example.com");
+                assert_eq!(&email.subject, "Hello world");
+                assert_eq!(&email.body_text, "Welcome\r\n");
+                assert_eq!(&email.body_html, "<p>Welcome</p>\r\n");
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chunking_not_advertised() {
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        let address = server.address().unwrap();
+        tokio::select! {
+            result = server.try_receive() => {
+                panic!("unexpected result: {result:?}")
+            }
+            _ = async move {
+                let mut socket = connect_raw(address).await;
+                let reply =
+                    command_raw(&mut socket, b"EHLO [127.0.0.1]\r\n").await;
+                assert!(!reply.contains("CHUNKING"), "{reply:?}");
+                for (command, expected) in [
+                    ("MAIL FROM:<sender@example.com>\r\n", "250 "),
+                    ("RCPT TO:<recipient@example.com>\r\n", "250 "),
+                    ("BDAT 5 LAST\r\n", "502 5.5.1 "),
+                    ("RSET\r\n", "250 "),
+                    ("QUIT\r\n", "221 "),
+                ] {
+                    let reply =
+                        command_raw(&mut socket, command.as_bytes()).await;
+                    assert!(reply.starts_with(expected), "{reply:?}");
+                }
+            } => {}
         }
     }
+
+    #[tokio::test]
+    async fn test_send_dsn_parameters() {
+        use crate::{DsnNotify, DsnReturn};
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        let address = server.address().unwrap();
+        tokio::join!(
+            async move {
+                let mut message = build_message(
+                    ("Sender", "sender@example.com"),
+                    ("Recipient", "recipient@example.com"),
+                    "Hello world",
+                    "Welcome",
+                    "<p>Welcome</p>",
+                )
+                .formatted();
+                message.extend(b"\r\n.\r\n");
+                let mut socket = connect_raw(address).await;
+                let reply =
+                    command_raw(&mut socket, b"EHLO [127.0.0.1]\r\n").await;
+                assert!(reply.contains("250-DSN\r\n"), "{reply:?}");
+                let reply = command_raw(
+                    &mut socket,
+                    b"MAIL FROM:<sender@example.com> RET=BODY\r\n",
+                )
+                .await;
+                assert!(reply.starts_with("555 5.5.4 "), "{reply:?}");
+                for command in [
+                    "MAIL FROM:<sender@example.com> RET=HDRS ENVID=QQ314159 \
+                     SIZE=1000 SMTPUTF8\r\n",
+                    "RCPT TO:<unknown@example.com> NOTIFY=NEVER,DELAY\r\n",
+                    "RCPT TO:<recipient@example.com> NOTIFY=FAILURE,DELAY\r\n",
+                    "RCPT TO:<other@example.com> NOTIFY=NEVER \
+                     ORCPT=rfc822;other+2Bx@example.com X-PRIORITY=1\r\n",
+                    "DATA\r\n",
+                ] {
+                    let reply =
+                        command_raw(&mut socket, command.as_bytes()).await;
+                    if command.starts_with("RCPT TO:<unknown@") {
+                        assert!(reply.starts_with("555 5.5.4 "), "{reply:?}");
+                        continue;
+                    }
+                    assert!(
+                        reply.starts_with('2') || reply.starts_with('3'),
+                        "{reply:?}"
+                    );
+                }
+                let reply = command_raw(&mut socket, &message).await;
+                assert!(reply.starts_with("250 "), "{reply:?}");
+                let reply = command_raw(&mut socket, b"QUIT\r\n").await;
+                assert!(reply.starts_with("221 "), "{reply:?}");
+            },
+            async move {
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                assert_eq!(&email.address_to, "recipient@example.com");
+                assert_eq!(email.dsn_return, Some(DsnReturn::Headers));
+                assert_eq!(email.dsn_envelope_id.as_deref(), Some("QQ314159"));
+                assert_eq!(
+                    email.mail_parameters,
+                    [
+                        ("SIZE".to_string(), Some("1000".to_string())),
+                        ("SMTPUTF8".to_string(), None),
+                    ]
+                );
+                assert_eq!(email.recipients.len(), 2);
+                let recipient =
+                    email.recipient("recipient@example.com").unwrap();
+                assert_eq!(
+                    recipient.notify,
+                    Some(vec![DsnNotify::Failure, DsnNotify::Delay])
+                );
+                assert_eq!(recipient.original_recipient, None);
+                assert_eq!(recipient.parameters, []);
+                let recipient = email.recipient("other@example.com").unwrap();
+                assert_eq!(recipient.notify, Some(vec![DsnNotify::Never]));
+                assert_eq!(
+                    recipient.original_recipient.as_deref(),
+                    Some("rfc822;other+x@example.com")
+                );
+                assert_eq!(
+                    recipient.parameters,
+                    [("X-PRIORITY".to_string(), Some("1".to_string()))]
+                );
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reject_recipient() {
+        use lettre::AsyncTransport;
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        server.reject_recipient("recipient@example.com");
+        let address = server.address().unwrap();
+        let client: SmtpClient = build_client(address).build();
+        let message = build_message(
+            ("Sender", "sender@example.com"),
+            ("Recipient", "recipient@example.com"),
+            "Hello world",
+            "Welcome",
+            "<p>Welcome</p>",
+        );
+        tokio::select! {
+            result = server.try_receive() => {
+                panic!("unexpected result: {result:?}")
+            }
+            result = timeout("sending email", client.send(message)) => {
+                let error = result.expect_err("expected rejection");
+                assert!(error.is_permanent(), "{error:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rules() {
+        use crate::{Action, Matcher, Reply, Rule};
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        server.add_rule(Rule::sender(
+            Matcher::glob("*@spam.example"),
+            Action::Reply(Reply::new(550, None, "Sender refused")),
+        ));
+        server.add_rule(Rule::recipient(
+            Matcher::Exact("ok@blocked.example".to_string()),
+            Action::Accept,
+        ));
+        server.add_rule(Rule::recipient(
+            Matcher::glob("*@blocked.example"),
+            Action::Reply(Reply::new(550, None, "Blocked")),
+        ));
+        server.add_rule(Rule::recipient(
+            Matcher::regex("^slow@").unwrap(),
+            Action::Reply(Reply::new(450, None, "Try again later")),
+        ));
+        let address = server.address().unwrap();
+        tokio::join!(
+            async move {
+                let mut message = build_message(
+                    ("Sender", "sender@example.com"),
+                    ("Recipient", "ok@blocked.example"),
+                    "Hello world",
+                    "Welcome",
+                    "<p>Welcome</p>",
+                )
+                .formatted();
+                message.extend(b"\r\n.\r\n");
+                let mut socket = connect_raw(address).await;
+                for (command, expected) in [
+                    ("EHLO client.example.com\r\n", "250"),
+                    ("MAIL FROM:<someone@spam.example>\r\n", "550 Sender"),
+                    ("RSET\r\n", "250"),
+                    ("MAIL FROM:<someone@spam.example>\r\n", "550 Sender"),
+                    ("MAIL FROM:<sender@example.com>\r\n", "250"),
+                    ("RCPT TO:<other@blocked.example>\r\n", "550 Blocked"),
+                    ("RCPT TO:<slow@example.com>\r\n", "450 Try again"),
+                    ("RCPT TO:<ok@blocked.example>\r\n", "250"),
+                    ("DATA\r\n", "354"),
+                ] {
+                    let reply =
+                        command_raw(&mut socket, command.as_bytes()).await;
+                    assert!(reply.starts_with(expected), "{reply:?}");
+                }
+                let reply = command_raw(&mut socket, &message).await;
+                assert!(reply.starts_with("250 "), "{reply:?}");
+                let reply = command_raw(&mut socket, b"QUIT\r\n").await;
+                assert!(reply.starts_with("221 "), "{reply:?}");
+            },
+            async {
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                assert_eq!(&email.address_from, "sender@example.com");
+                assert_eq!(email.recipients.len(), 1);
+                assert_eq!(&email.address_to, "ok@blocked.example");
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_greylisting() {
+        use crate::{Clock, Greylisting, ManualClock};
+        use lettre::AsyncTransport;
+        let clock = ManualClock::new();
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        server.set_greylisting(Some(Greylisting {
+            delay: Duration::from_secs(300),
+            clock: Clock::Manual(clock.clone()),
+        }));
+        let address = server.address().unwrap();
+        let client: SmtpClient = build_client(address).build();
+        let message = build_message(
+            ("Sender", "sender@example.com"),
+            ("Recipient", "recipient@example.com"),
+            "Hello world",
+            "Welcome",
+            "<p>Welcome</p>",
+        );
+        for advance in [0, 299] {
+            clock.advance(Duration::from_secs(advance));
+            tokio::select! {
+                result = server.try_receive() => {
+                    panic!("unexpected result: {result:?}")
+                }
+                result = timeout(
+                    "sending email", client.send(message.clone()),
+                ) => {
+                    let error = result.expect_err("expected greylisting");
+                    assert!(error.is_transient(), "{error:?}");
+                }
+            }
+        }
+        clock.advance(Duration::from_secs(1));
+        tokio::join!(
+            async {
+                timeout("sending email", client.send(message))
+                    .await
+                    .expect("error sending email");
+            },
+            async {
+                timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        use crate::{Clock, Limits, ManualClock, RateLimit};
+        let clock = ManualClock::new();
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        server.set_limits(Limits {
+            max_connections: Some(1),
+            max_messages_per_connection: Some(1),
+            max_recipients: Some(1),
+            rate: Some(RateLimit {
+                clock: Clock::Manual(clock.clone()),
+                ..RateLimit::new(1, Duration::from_secs(60))
+            }),
+        });
+        let address = server.address().unwrap();
+        let mut message = build_message(
+            ("Sender", "sender@example.com"),
+            ("Recipient", "recipient@example.com"),
+            "Hello world",
+            "Welcome",
+            "<p>Welcome</p>",
+        )
+        .formatted();
+        message.extend(b"\r\n.\r\n");
+        let client = async move {
+            let mut socket = connect_raw(address).await;
+            let socket2 = TcpStream::connect(address).await.unwrap();
+            let greeting = read_reply(&mut BufReader::new(socket2)).await;
+            assert!(greeting.starts_with("421 4.7.0 "), "{greeting:?}");
+            for (command, expected) in [
+                ("EHLO client.example.com\r\n", "250"),
+                ("MAIL FROM:<sender@example.com>\r\n", "250"),
+                ("RCPT TO:<recipient@example.com>\r\n", "250"),
+                ("RCPT TO:<other@example.com>\r\n", "452 4.5.3 "),
+                ("DATA\r\n", "354"),
+            ] {
+                let reply = command_raw(&mut socket, command.as_bytes()).await;
+                assert!(reply.starts_with(expected), "{reply:?}");
+            }
+            let reply = command_raw(&mut socket, &message).await;
+            assert!(reply.starts_with("250 "), "{reply:?}");
+            let reply =
+                command_raw(&mut socket, b"MAIL FROM:<sender@example.com>\r\n")
+                    .await;
+            assert!(reply.starts_with("421 4.7.0 "), "{reply:?}");
+
+            let mut socket = connect_raw(address).await;
+            for (command, expected) in [
+                ("HELO client.example.com\r\n", "250"),
+                ("MAIL FROM:<sender@example.com>\r\n", "451 4.7.1 "),
+            ] {
+                let reply = command_raw(&mut socket, command.as_bytes()).await;
+                assert!(reply.starts_with(expected), "{reply:?}");
+            }
+            clock.advance(Duration::from_secs(60));
+            // an aborted transaction does not count towards the rate
+            for (command, expected) in [
+                ("MAIL FROM:<sender@example.com>\r\n", "250 "),
+                ("RSET\r\n", "250 "),
+                ("MAIL FROM:<sender@example.com>\r\n", "250 "),
+            ] {
+                let reply = command_raw(&mut socket, command.as_bytes()).await;
+                assert!(reply.starts_with(expected), "{reply:?}");
+            }
+            let reply = command_raw(&mut socket, b"QUIT\r\n").await;
+            assert!(reply.starts_with("221 "), "{reply:?}");
+        };
+        let receiver = async {
+            let email = timeout("receiving email", server.try_receive())
+                .await
+                .expect("error receiving email");
+            assert_eq!(email.recipients.len(), 1);
+            server.try_receive().await
+        };
+        tokio::select! {
+            result = receiver => panic!("unexpected result: {result:?}"),
+            _ = client => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multiple_messages_per_connection() {
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        let address = server.address().unwrap();
+        tokio::join!(
+            async move {
+                let mut socket = connect_raw(address).await;
+                let reply =
+                    command_raw(&mut socket, b"EHLO client.example.com\r\n")
+                        .await;
+                assert!(reply.starts_with("250"), "{reply:?}");
+                for subject in ["First", "Second"] {
+                    let mut message = build_message(
+                        ("Sender", "sender@example.com"),
+                        ("Recipient", "recipient@example.com"),
+                        subject,
+                        "Welcome",
+                        "<p>Welcome</p>",
+                    )
+                    .formatted();
+                    message.extend(b"\r\n.\r\n");
+                    for command in [
+                        "RSET\r\n",
+                        "MAIL FROM:<sender@example.com>\r\n",
+                        "RCPT TO:<recipient@example.com>\r\n",
+                        "DATA\r\n",
+                    ] {
+                        let reply =
+                            command_raw(&mut socket, command.as_bytes()).await;
+                        assert!(
+                            reply.starts_with('2') || reply.starts_with('3'),
+                            "{reply:?}"
+                        );
+                    }
+                    let reply = command_raw(&mut socket, &message).await;
+                    assert!(reply.starts_with("250 "), "{reply:?}");
+                }
+                let reply = command_raw(&mut socket, b"QUIT\r\n").await;
+                assert!(reply.starts_with("221 "), "{reply:?}");
+            },
+            async {
+                for subject in ["First", "Second"] {
+                    let email =
+                        timeout("receiving email", server.try_receive())
+                            .await
+                            .expect("error receiving email");
+                    assert_eq!(&email.subject, subject);
+                    assert_eq!(&email.helo_name, "client.example.com");
+                }
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transcripts() {
+        use crate::Direction;
+        let mut server = start_server(Auth::Login {
+            username: "user".to_string(),
+            password: "secret".to_string(),
+        })
+        .await;
+        let address = server.address().unwrap();
+        let mut client: SmtpClient = build_client(address)
+            .credentials(Credentials::new(
+                "user".to_string(),
+                "secret".to_string(),
+            ))
+            .build();
+        let email = tokio::join!(
+            send(
+                &mut client,
+                ("Sender", "sender@example.com"),
+                ("Recipient", "recipient@example.com"),
+                "Hello world",
+                "Welcome",
+                "<p>Welcome</p>",
+            ),
+            timeout("receiving email", server.try_receive()),
+        )
+        .1
+        .expect("error receiving email");
+        let lines = email
+            .transcript
+            .lines
+            .iter()
+            .map(|line| (line.direction, line.line.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(lines[0], (Direction::Sent, "220 127.0.0.1"));
+        assert!(lines.contains(&(Direction::Received, "AUTH PLAIN ****")));
+        assert!(lines.contains(&(Direction::Received, "Subject: Hello world")));
+        assert_eq!(lines[lines.len() - 2], (Direction::Received, "QUIT"));
+        assert!(email.transcript.to_string().contains(" C: QUIT\n"));
+
+        let client = async move {
+            let mut socket = connect_raw(address).await;
+            command_raw(&mut socket, b"EHLO client.example.com\r\n").await;
+            command_raw(&mut socket, b"AUTH PLAIN AHVzZXIAc2VjcmV0\r\n").await;
+            socket.get_mut().write_all(b"BOGUS\r\n").await.unwrap();
+            socket
+        };
+        let (_socket, result) =
+            tokio::join!(client, timeout("receiving", server.try_receive()));
+        let error = result.expect_err("expected error");
+        assert!(
+            matches!(error, super::Error::Smtp(..)),
+            "unexpected error: {error:?}"
+        );
+        let transcript = error.transcript().expect("missing transcript");
+        assert_eq!(transcript.client_ip, Some(address.ip()));
+        let last = transcript.lines.last().unwrap();
+        assert_eq!(
+            (last.direction, last.line.as_str()),
+            (Direction::Received, "BOGUS")
+        );
+
+        assert_eq!(server.transcripts().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_charsets() {
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        let address = server.address().unwrap();
+        let message = "From: =?UTF-8?B?SsO8cmdlbg==?= <sender@example.com>\r\n\
+                       To: <recipient@example.com>\r\n\
+                       Subject: =?UTF-8?B?R3LDvMOfZSBhdXMgS8O2bG4=?=\r\n\
+                       MIME-Version: 1.0\r\n\
+                       Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+                       \r\n\
+                       --b\r\n\
+                       Content-Type: text/plain; charset=ISO-8859-1\r\n\
+                       Content-Transfer-Encoding: quoted-printable\r\n\
+                       \r\n\
+                       Gr=FC=DFe\r\n\
+                       --b\r\n\
+                       Content-Type: text/html; charset=Shift_JIS\r\n\
+                       Content-Transfer-Encoding: base64\r\n\
+                       \r\n\
+                       PHA+grGC8YLJgr+CzTwvcD4=\r\n\
+                       --b--\r\n\
+                       .\r\n";
+        tokio::join!(
+            async move {
+                let mut socket = connect_raw(address).await;
+                for command in [
+                    "EHLO client.example.com\r\n",
+                    "MAIL FROM:<sender@example.com>\r\n",
+                    "RCPT TO:<recipient@example.com>\r\n",
+                    "DATA\r\n",
+                    message,
+                    "QUIT\r\n",
+                ] {
+                    let reply =
+                        command_raw(&mut socket, command.as_bytes()).await;
+                    assert!(
+                        reply.starts_with('2') || reply.starts_with('3'),
+                        "{reply:?}"
+                    );
+                }
+            },
+            async {
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                assert_eq!(&email.subject, "Grüße aus Köln");
+                assert_eq!(
+                    email.headers.get_raw("subject"),
+                    Some("=?UTF-8?B?R3LDvMOfZSBhdXMgS8O2bG4=?=")
+                );
+                assert_eq!(
+                    email.get_from(),
+                    Some("Jürgen <sender@example.com>")
+                );
+                assert_eq!(&email.body_text, "Grüße\r\n");
+                assert_eq!(&email.body_html, "<p>こんにちは</p>");
+                let parts = email
+                    .parts
+                    .iter()
+                    .map(|part| {
+                        (
+                            part.mimetype.as_str(),
+                            part.charset.as_str(),
+                            part.transfer_encoding.as_str(),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    parts,
+                    [
+                        ("text/plain", "iso-8859-1", "quoted-printable"),
+                        ("text/html", "shift_jis", "base64"),
+                    ]
+                );
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_threading_headers() {
+        use std::time::{Duration, UNIX_EPOCH};
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        let address = server.address().unwrap();
+        let message = "From: <sender@example.com>\r\n\
+                       To: <recipient@example.com>\r\n\
+                       Subject: Re: Hello\r\n\
+                       Date: Tue, 1 Jul 2003 10:52:37 +0200\r\n\
+                       Message-ID: <3@example.com>\r\n\
+                       In-Reply-To: <2@example.com>\r\n\
+                       References: <1@example.com>\r\n <2@example.com>\r\n\
+                       MIME-Version: 1.0\r\n\
+                       Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+                       \r\n\
+                       --b\r\n\
+                       Content-Type: text/plain\r\n\
+                       \r\n\
+                       Reply\r\n\
+                       --b\r\n\
+                       Content-Type: text/html\r\n\
+                       \r\n\
+                       <p>Reply</p>\r\n\
+                       --b--\r\n\
+                       .\r\n";
+        tokio::join!(
+            async move {
+                let mut socket = connect_raw(address).await;
+                for command in [
+                    "EHLO client.example.com\r\n",
+                    "MAIL FROM:<sender@example.com>\r\n",
+                    "RCPT TO:<recipient@example.com>\r\n",
+                    "DATA\r\n",
+                    message,
+                    "QUIT\r\n",
+                ] {
+                    let reply =
+                        command_raw(&mut socket, command.as_bytes()).await;
+                    assert!(
+                        reply.starts_with('2') || reply.starts_with('3'),
+                        "{reply:?}"
+                    );
+                }
+            },
+            async {
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                assert_eq!(
+                    email.date(),
+                    Some(UNIX_EPOCH + Duration::from_secs(1057049557))
+                );
+                assert_eq!(
+                    email.message_id().as_deref(),
+                    Some("3@example.com")
+                );
+                assert_eq!(email.in_reply_to(), ["2@example.com"]);
+                assert_eq!(
+                    email.references(),
+                    ["1@example.com", "2@example.com"]
+                );
+                assert_eq!(
+                    email.thread_key().as_deref(),
+                    Some("1@example.com")
+                );
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_threads() {
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        let address = server.address().unwrap();
+        let message = |subject: &str, id: &str, reply_headers: &str| {
+            format!(
+                "From: <sender@example.com>\r\n\
+                 To: <recipient@example.com>\r\n\
+                 Subject: {subject}\r\n\
+                 Message-ID: <{id}>\r\n\
+                 {reply_headers}\
+                 MIME-Version: 1.0\r\n\
+                 Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+                 \r\n\
+                 --b\r\n\
+                 Content-Type: text/plain\r\n\
+                 \r\n\
+                 {subject}\r\n\
+                 --b\r\n\
+                 Content-Type: text/html\r\n\
+                 \r\n\
+                 <p>{subject}</p>\r\n\
+                 --b--\r\n\
+                 .\r\n"
+            )
+        };
+        let messages = [
+            message("Ticket created", "1@example.com", ""),
+            message("Unrelated", "other@example.com", ""),
+            message(
+                "Ticket updated",
+                "2@example.com",
+                "In-Reply-To: <1@example.com>\r\n\
+                 References: <1@example.com>\r\n",
+            ),
+            message(
+                "Re: Lost",
+                "3@example.com",
+                "In-Reply-To: <lost@example.com>\r\n",
+            ),
+            // only refers to the update, not the ticket itself
+            message(
+                "Ticket closed",
+                "4@example.com",
+                "In-Reply-To: <2@example.com>\r\n",
+            ),
+        ];
+        tokio::join!(
+            async move {
+                let mut socket = connect_raw(address).await;
+                command_raw(&mut socket, b"EHLO client.example.com\r\n").await;
+                for message in &messages {
+                    for command in [
+                        "MAIL FROM:<sender@example.com>\r\n",
+                        "RCPT TO:<recipient@example.com>\r\n",
+                        "DATA\r\n",
+                        message,
+                    ] {
+                        let reply =
+                            command_raw(&mut socket, command.as_bytes()).await;
+                        assert!(
+                            reply.starts_with('2') || reply.starts_with('3'),
+                            "{reply:?}"
+                        );
+                    }
+                }
+                command_raw(&mut socket, b"QUIT\r\n").await;
+            },
+            async {
+                for _ in 0..5 {
+                    timeout("receiving email", server.try_receive())
+                        .await
+                        .expect("error receiving email");
+                }
+            },
+        );
+        assert_eq!(server.received().len(), 5);
+        let threads = server.threads();
+        let subjects = threads
+            .iter()
+            .map(|thread| {
+                thread
+                    .emails
+                    .iter()
+                    .map(|email| email.subject.as_str())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            subjects,
+            [
+                vec!["Ticket created", "Ticket updated", "Ticket closed"],
+                vec!["Unrelated"],
+                vec!["Re: Lost"],
+            ]
+        );
+        assert_eq!(threads[0].root().unwrap().subject, "Ticket created");
+        assert!(!threads[0].is_orphan());
+        assert!(threads[0].missing().is_empty());
+        assert!(threads[2].is_orphan());
+        assert_eq!(threads[2].missing(), ["lost@example.com"]);
+    }
+
+    #[cfg(feature = "dkim")]
+    #[tokio::test]
+    async fn test_dkim() {
+        use crate::{DkimFailure, DkimKeys, DkimResult};
+        // signed with an ed25519 key using relaxed canonicalization
+        // and an rsa key using simple canonicalization
+        let signed = "DKIM-Signature: v=1; a=ed25519-sha256; \
+            c=relaxed/relaxed; d=example.com; s=ed;\r\n \
+            h=From:To:Subject; \
+            bh=J4DbD6whvduWsg9SryvEutHsxCKDU5hlHOj8H7d1ReY=;\r\n \
+            b=VzKAwcrxH/+DjuTYSJ5e/0uPiJ9JYMlKwUcUfcCv02IS3X\
+            sLYSn7RKvVHTYIFgWo\r\n \
+            NhhHSAMOT0cV9rNvKwKSDg==\r\n\
+            DKIM-Signature: v=1; a=rsa-sha256; \
+            c=simple/simple; d=example.com; s=rsa;\r\n \
+            h=From:To:Subject; \
+            bh=z4zDo71YLFVk+qFHZf3n1mkzrpIyrQKI1N5uwZAvB/8=;\r\n \
+            b=GRZokx2R3zl/WsFYTkaraU4i9HIhR6ci8zvh5cSbr2nuQp\
+            urg1YpC40WnJ8gHrox\r\n \
+            wRftnkpS/Rwb1qpGpJc6bSfWaLIrEdp13ptlLoLUAVrJEKSTs/\
+            2OH5tZGbmZAMCG\r\n \
+            nZezVO/O7vbhxNFmNDHKTQWT1Kjburv/EamfP2vA50M=\r\n\
+            From: Sender <sender@example.com>\r\n\
+            To: <recipient@example.com>\r\n\
+            Subject:  Signed   message\r\n\
+            MIME-Version: 1.0\r\n\
+            Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+            \r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            Hello  world\r\n\
+            --b\r\n\
+            Content-Type: text/html\r\n\
+            \r\n\
+            <p>Hello world</p>\r\n\
+            --b--\r\n\
+            \r\n";
+        let messages = [
+            signed.to_string(),
+            // rewriting whitespace only breaks simple canonicalization
+            signed.replace(
+                "Subject:  Signed   message",
+                "Subject: Signed message",
+            ),
+            signed.replace("Hello  world", "Hello  World"),
+        ];
+        let mut keys = DkimKeys::new();
+        keys.insert(
+            "ed",
+            "example.com",
+            "v=DKIM1; k=ed25519; \
+             p=A6EHv/POEL4dcN0Y50vAmWfk1jCbpQ1fHdyGZBJVMbg=",
+        );
+        keys.insert(
+            "rsa",
+            "Example.com",
+            "v=DKIM1; \
+             p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDKdhicjisKusjcXU9u\
+             /6/oP3dwApCtc8ZhZaQRHKn/K7r444Quwq6oRgEPwfjMKWrqQ0dx57Ukr59\
+             Ynr1wEzz7Rlde1f5tzFoGTwOXtA8k798Ho4MGe9zoXQmsvhYNM6NMHrWmctS\
+             58JEVuOifNUwJCcfpdCOIbl1GaVsccCb/7wIDAQAB",
+        );
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        server.set_dkim_keys(keys);
+        let address = server.address().unwrap();
+        tokio::join!(
+            async move {
+                let mut socket = connect_raw(address).await;
+                command_raw(&mut socket, b"EHLO client.example.com\r\n").await;
+                for message in &messages {
+                    for command in [
+                        "MAIL FROM:<sender@example.com>\r\n",
+                        "RCPT TO:<recipient@example.com>\r\n",
+                        "DATA\r\n",
+                        &format!("{message}.\r\n"),
+                    ] {
+                        let reply =
+                            command_raw(&mut socket, command.as_bytes()).await;
+                        assert!(
+                            reply.starts_with('2') || reply.starts_with('3'),
+                            "{reply:?}"
+                        );
+                    }
+                }
+                command_raw(&mut socket, b"QUIT\r\n").await;
+            },
+            async {
+                let mut results = Vec::new();
+                for _ in 0..3 {
+                    let email =
+                        timeout("receiving email", server.try_receive())
+                            .await
+                            .expect("error receiving email");
+                    assert_eq!(email.dkim.len(), 2);
+                    assert_eq!(email.dkim[0].domain, "example.com");
+                    assert_eq!(email.dkim[0].selector, "ed");
+                    assert_eq!(email.dkim[1].selector, "rsa");
+                    results.push((
+                        email.dkim[0].result.clone(),
+                        email.dkim[1].result.clone(),
+                    ));
+                }
+                use DkimFailure::{BodyHashMismatch, SignatureMismatch};
+                assert_eq!(
+                    results,
+                    [
+                        (DkimResult::Pass, DkimResult::Pass),
+                        (DkimResult::Pass, DkimResult::Fail(SignatureMismatch)),
+                        (
+                            DkimResult::Fail(BodyHashMismatch),
+                            DkimResult::Fail(BodyHashMismatch)
+                        ),
+                    ]
+                );
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trace_headers() {
+        let mut server = start_server(Auth::AcceptAll).await;
+        server.set_hostname(Some("mx.example.com".to_string()));
+        server.set_trace_headers(true);
+        server.allow_sender_ip("example.com", "127.0.0.1".parse().unwrap());
+        server.allow_sender_ip("other.example", "192.0.2.1".parse().unwrap());
+        let address = server.address().unwrap();
+        tokio::join!(
+            async move {
+                let mut socket = connect_raw(address).await;
+                for command in [
+                    "EHLO client.example.com\r\n",
+                    "AUTH PLAIN AHVzZXIAc2VjcmV0\r\n",
+                ] {
+                    command_raw(&mut socket, command.as_bytes()).await;
+                }
+                for sender in [
+                    "sender@example.com",
+                    "sender@other.example",
+                    "\"x; spf=pass\"@other.example",
+                ] {
+                    for command in [
+                        &format!("MAIL FROM:<{sender}>\r\n"),
+                        "RCPT TO:<recipient@example.com>\r\n",
+                        "DATA\r\n",
+                        &format!(
+                            "From: <{sender}>\r\n\
+                             To: <recipient@example.com>\r\n\
+                             Subject: Traced\r\n\
+                             Content-Type: multipart/alternative; \
+                             boundary=\"b\"\r\n\
+                             \r\n\
+                             --b\r\n\
+                             Content-Type: text/plain\r\n\
+                             \r\n\
+                             Hello\r\n\
+                             --b\r\n\
+                             Content-Type: text/html\r\n\
+                             \r\n\
+                             <p>Hello</p>\r\n\
+                             --b--\r\n\
+                             .\r\n"
+                        ),
+                    ] {
+                        let reply =
+                            command_raw(&mut socket, command.as_bytes()).await;
+                        assert!(
+                            reply.starts_with('2') || reply.starts_with('3'),
+                            "{reply:?}"
+                        );
+                    }
+                }
+                command_raw(&mut socket, b"QUIT\r\n").await;
+            },
+            async {
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                let names = email
+                    .headers
+                    .iter()
+                    .take(2)
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>();
+                assert_eq!(names, ["Received", "Authentication-Results"]);
+                let received = email.headers.get("Received").unwrap();
+                assert!(
+                    received.starts_with(
+                        "from client.example.com ([127.0.0.1]) \
+                         by mx.example.com with ESMTPA \
+                         for <recipient@example.com>; "
+                    ),
+                    "{received:?}"
+                );
+                let date = received.rsplit_once("; ").unwrap().1;
+                assert!(mailparse::dateparse(date).is_ok(), "{date:?}");
+                let mut expected = "mx.example.com; \
+                                    auth=pass smtp.auth=user; \
+                                    spf=pass smtp.mailfrom=sender@example.com"
+                    .to_string();
+                if cfg!(feature = "dkim") {
+                    expected.push_str("; dkim=none");
+                }
+                assert_eq!(
+                    email.headers.get("Authentication-Results"),
+                    Some(expected.as_str())
+                );
+
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                assert!(email
+                    .headers
+                    .get("Authentication-Results")
+                    .unwrap()
+                    .contains("spf=fail smtp.mailfrom=sender@other.example"));
+
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                let results =
+                    email.headers.get("Authentication-Results").unwrap();
+                assert!(
+                    results.contains(
+                        "; spf=fail \
+                         smtp.mailfrom=\"\\\"x; spf=pass\\\"@other.example\""
+                    ),
+                    "{results:?}"
+                );
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spf() {
+        use crate::{Spf, SpfResult};
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        let mut spf = Spf::new([
+            ("example.com", "v=spf1 ip4:127.0.0.0/8 -all"),
+            ("blocked.example", "v=spf1 ip4:192.0.2.1 -all"),
+            ("soft.example", "v=spf1 ip4:192.0.2.1 ~all"),
+        ]);
+        spf.reject_failures = true;
+        server.set_spf(Some(spf));
+        let address = server.address().unwrap();
+        let message = |sender: &str| {
+            format!(
+                "From: <{sender}>\r\n\
+                 To: <recipient@example.com>\r\n\
+                 Subject: SPF\r\n\
+                 Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+                 \r\n\
+                 --b\r\n\
+                 Content-Type: text/plain\r\n\
+                 \r\n\
+                 Hello\r\n\
+                 --b\r\n\
+                 Content-Type: text/html\r\n\
+                 \r\n\
+                 <p>Hello</p>\r\n\
+                 --b--\r\n\
+                 .\r\n"
+            )
+        };
+        tokio::join!(
+            async move {
+                let mut socket = connect_raw(address).await;
+                command_raw(&mut socket, b"EHLO client.example.com\r\n").await;
+                let reply = command_raw(
+                    &mut socket,
+                    b"MAIL FROM:<sender@blocked.example>\r\n",
+                )
+                .await;
+                assert!(reply.starts_with("550 5.7.23 "), "{reply:?}");
+                for sender in ["sender@example.com", "sender@soft.example"] {
+                    for command in [
+                        &format!("MAIL FROM:<{sender}>\r\n"),
+                        "RCPT TO:<recipient@example.com>\r\n",
+                        "DATA\r\n",
+                        &message(sender),
+                    ] {
+                        let reply =
+                            command_raw(&mut socket, command.as_bytes()).await;
+                        assert!(
+                            reply.starts_with('2') || reply.starts_with('3'),
+                            "{reply:?}"
+                        );
+                    }
+                }
+                command_raw(&mut socket, b"QUIT\r\n").await;
+            },
+            async {
+                for expected in [SpfResult::Pass, SpfResult::SoftFail] {
+                    let email =
+                        timeout("receiving email", server.try_receive())
+                            .await
+                            .expect("error receiving email");
+                    assert_eq!(email.spf, Some(expected));
+                }
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_links() {
+        use regex::Regex;
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        let address = server.address().unwrap();
+        let mut client: SmtpClient = build_client(address).build();
+        tokio::join!(
+            async move {
+                send(
+                    &mut client,
+                    ("Sender", "sender@example.com"),
+                    ("Recipient", "recipient@example.com"),
+                    "Reset your password",
+                    "Reset your password at \
+                     https://app.example.com/reset?token=abc123.",
+                    "<p><a href=\"https://click.example.com/?\
+                     u=https%3A%2F%2Fapp.example.com%2Freset%3Ftoken%3Dabc123\
+                     &amp;c=1\">Reset your password</a></p>",
+                )
+                .await;
+            },
+            async {
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                assert_eq!(email.links().len(), 2);
+                let link = email
+                    .find_link(&Regex::new(r"/reset\?token=").unwrap())
+                    .unwrap();
+                assert_eq!(link.text.as_deref(), Some("Reset your password"));
+                assert_eq!(
+                    link.target(),
+                    "https://app.example.com/reset?token=abc123"
+                );
+                assert!(email
+                    .find_link(&Regex::new("/unsubscribe").unwrap())
+                    .is_none());
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_receive_code() {
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        let address = server.address().unwrap();
+        let mut client: SmtpClient = build_client(address).build();
+        tokio::join!(
+            async move {
+                for (recipient, code) in [
+                    ("other@example.com", "111111"),
+                    ("new-user@example.com", "042917"),
+                ] {
+                    send(
+                        &mut client,
+                        ("Sender", "sender@example.com"),
+                        ("Recipient", recipient),
+                        "Your verification code",
+                        &format!("Your code is {code}."),
+                        &format!("<p>Your code is <b>{code}</b></p>"),
+                    )
+                    .await;
+                }
+            },
+            async {
+                let email = server
+                    .receive_to("New-User@example.com", TIMEOUT)
+                    .await
+                    .expect("timeout receiving email");
+                assert_eq!(email.code().as_deref(), Some("042917"));
+            },
+        );
+        assert_eq!(server.received().len(), 2);
+        assert!(server
+            .receive_to("new-user@example.com", Duration::from_millis(10))
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_text_alternative() {
+        use crate::TextMismatch;
+
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        let address = server.address().unwrap();
+        let mut client: SmtpClient = build_client(address).build();
+        tokio::join!(
+            async move {
+                send(
+                    &mut client,
+                    ("Sender", "sender@example.com"),
+                    ("Recipient", "recipient@example.com"),
+                    "Your order",
+                    "Thanks for your order.\n\n\
+                     Track it at https://example.com/track\n\n\
+                     Use code SPRING for 10% off your next order.",
+                    "<p>Thanks for your order.</p>\
+                     <p><a href=\"https://example.com/track\">Track it</a>\
+                     </p><p>It ships <b>tomorrow</b>.</p>",
+                )
+                .await;
+            },
+            async {
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                assert_eq!(
+                    email.html_to_text(),
+                    "Thanks for your order.\n\n\
+                     Track it (https://example.com/track)\n\n\
+                     It ships tomorrow."
+                );
+                assert!(email.body_text.contains("\r\n\r\n"));
+                assert_eq!(
+                    email.check_text_alternative(),
+                    [
+                        TextMismatch::MissingParagraph(
+                            "It ships tomorrow.".to_string()
+                        ),
+                        TextMismatch::ExtraParagraph(
+                            "Use code SPRING for 10% off your next order."
+                                .to_string()
+                        ),
+                    ]
+                );
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bounce_mailbox() {
+        use crate::BounceDelivery;
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        server.reject_recipient("recipient@example.com");
+        server.set_bounces(Some(BounceDelivery::Mailbox));
+        let address = server.address().unwrap();
+        let mut client: SmtpClient = build_client(address).build();
+        tokio::join!(
+            async move {
+                send(
+                    &mut client,
+                    ("Sender", "sender@example.com"),
+                    ("Recipient", "recipient@example.com"),
+                    "Hello world",
+                    "Welcome",
+                    "<p>Welcome</p>",
+                )
+                .await;
+                send(
+                    &mut client,
+                    ("Sender", "sender@example.com"),
+                    ("Other", "other@example.com"),
+                    "Second",
+                    "Welcome",
+                    "<p>Welcome</p>",
+                )
+                .await;
+            },
+            async {
+                // the message is only delivered to the other recipient
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                assert_eq!(&email.subject, "Second");
+                assert_eq!(&email.address_to, "other@example.com");
+            },
+        );
+        let mut bounces = server.bounces();
+        assert_eq!(bounces.len(), 1);
+        let bounce = bounces.pop().unwrap();
+        assert_eq!(&bounce.address_to, "sender@example.com");
+        assert_eq!(bounce.recipients.len(), 1);
+        assert_eq!(
+            &bounce.recipients[0].recipient.address,
+            "recipient@example.com"
+        );
+        assert_eq!(bounce.recipients[0].status.to_string(), "5.1.1");
+        let report = mailparse::parse_mail(&bounce.message).unwrap();
+        assert_eq!(report.ctype.mimetype, "multipart/report");
+        assert_eq!(report.subparts.len(), 3);
+        assert_eq!(
+            report.subparts[1].ctype.mimetype,
+            "message/delivery-status"
+        );
+        let status = report.subparts[1].get_body().unwrap();
+        assert!(
+            status.contains("Final-Recipient: rfc822; recipient@example.com"),
+            "{status}"
+        );
+        assert!(status.contains("Status: 5.1.1"), "{status}");
+        assert_eq!(report.subparts[2].ctype.mimetype, "message/rfc822");
+    }
+
+    #[tokio::test]
+    async fn test_bounce_callback() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::BounceDelivery;
+        let bounces = Arc::new(Mutex::new(Vec::new()));
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        server.reject_recipient("recipient@example.com");
+        server.set_bounce_return_path(Some("bounces@example.com".to_string()));
+        server.set_bounces(Some(BounceDelivery::Callback(Arc::new({
+            let bounces = bounces.clone();
+            move |bounce| bounces.lock().unwrap().push(bounce)
+        }))));
+        let address = server.address().unwrap();
+        let mut client: SmtpClient = build_client(address).build();
+        tokio::select! {
+            result = server.try_receive() => {
+                panic!("unexpected result: {result:?}")
+            }
+            _ = send(
+                &mut client,
+                ("Sender", "sender@example.com"),
+                ("Recipient", "recipient@example.com"),
+                "Hello world",
+                "Welcome",
+                "<p>Welcome</p>",
+            ) => {}
+        }
+        assert!(server.bounces().is_empty());
+        let bounces = bounces.lock().unwrap();
+        assert_eq!(bounces.len(), 1);
+        assert_eq!(&bounces[0].address_to, "bounces@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_identity() {
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        server.set_hostname(Some("mx.example.com".to_string()));
+        server.set_banner(Some("ESMTP Postfix".to_string()));
+        server.set_capabilities(Some(vec![
+            "PIPELINING".to_string(),
+            "SIZE 10240000".to_string(),
+            "X-UNKNOWN".to_string(),
+        ]));
+        let address = server.address().unwrap();
+        tokio::select! {
+            result = server.try_receive() => {
+                panic!("unexpected result: {result:?}")
+            }
+            _ = async move {
+                let socket = TcpStream::connect(address).await.unwrap();
+                let mut socket = BufReader::new(socket);
+                let greeting = read_reply(&mut socket).await;
+                assert_eq!(greeting, "220 mx.example.com ESMTP Postfix\r\n");
+                let reply =
+                    command_raw(&mut socket, b"EHLO [127.0.0.1]\r\n").await;
+                assert_eq!(
+                    reply,
+                    "250-mx.example.com\r\n\
+                     250-PIPELINING\r\n\
+                     250-SIZE 10240000\r\n\
+                     250 X-UNKNOWN\r\n"
+                );
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_8bitmime() {
+        use lettre::{
+            message::{
+                header::{ContentTransferEncoding, ContentType},
+                Body, MultiPart, SinglePart,
+            },
+            AsyncTransport, Message,
+        };
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        server.set_capabilities(Some(vec![
+            "8BITMIME".to_string(),
+            "SIZE 10240000".to_string(),
+        ]));
+        let address = server.address().unwrap();
+        let client: SmtpClient = build_client(address).build();
+        // NOTE: lettre only sends `BODY=8BITMIME` for non-ASCII content
+        let body = |content: &str| {
+            Body::new_with_encoding(
+                content.to_string(),
+                ContentTransferEncoding::EightBit,
+            )
+            .unwrap()
+        };
+        let message = Message::builder()
+            .from("Sender <sender@example.com>".parse().unwrap())
+            .to("Recipient <recipient@example.com>".parse().unwrap())
+            .subject("Hello world")
+            .multipart(
+                MultiPart::alternative()
+                    .singlepart(
+                        SinglePart::builder()
+                            .header(ContentType::TEXT_PLAIN)
+                            .body(body("Grüße")),
+                    )
+                    .singlepart(
+                        SinglePart::builder()
+                            .header(ContentType::TEXT_HTML)
+                            .body(body("<p>Grüße</p>")),
+                    ),
+            )
+            .unwrap();
+        tokio::join!(
+            async move {
+                timeout("sending email", client.send(message))
+                    .await
+                    .expect("error sending email message");
+            },
+            async move {
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                assert_eq!(&email.body_text, "Grüße\r\n");
+                assert_eq!(
+                    email.mail_parameters,
+                    [("BODY".to_string(), Some("8BITMIME".to_string()))]
+                );
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_helo_name() {
+        use lettre::transport::smtp::extension::ClientId;
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        let address = server.address().unwrap();
+        let mut client: SmtpClient = build_client(address)
+            .hello_name(ClientId::Domain("client.example.com".to_string()))
+            .build();
+        tokio::join!(
+            async move {
+                send(
+                    &mut client,
+                    ("Sender", "sender@example.com"),
+                    ("Recipient", "recipient@example.com"),
+                    "Hello world",
+                    "Welcome",
+                    "<p>Welcome</p>",
+                )
+                .await;
+            },
+            async move {
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                assert_eq!(&email.helo_name, "client.example.com");
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_helo_validator() {
+        use std::sync::Arc;
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        server.set_hostname(Some("mx.example.com".to_string()));
+        server.set_helo_validator(Some(Arc::new(|name| name.contains('.'))));
+        let address = server.address().unwrap();
+        tokio::select! {
+            result = server.try_receive() => {
+                panic!("unexpected result: {result:?}")
+            }
+            _ = async move {
+                let mut socket = connect_raw(address).await;
+                let reply =
+                    command_raw(&mut socket, b"HELO localhost\r\n").await;
+                assert!(reply.starts_with("501 5.5.4 "), "{reply:?}");
+                let reply =
+                    command_raw(&mut socket, b"HELO client.example.com\r\n")
+                        .await;
+                assert_eq!(reply, "250 mx.example.com\r\n");
+                let reply = command_raw(&mut socket, b"QUIT\r\n").await;
+                assert!(reply.starts_with("221 "), "{reply:?}");
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_expand_help() {
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        server.add_user("fred@example.com", Some("Fred Smith".to_string()));
+        server.add_mailing_list("staff", vec!["fred@example.com".to_string()]);
+        let address = server.address().unwrap();
+        tokio::select! {
+            result = server.try_receive() => {
+                panic!("unexpected result: {result:?}")
+            }
+            _ = async move {
+                let mut socket = connect_raw(address).await;
+                let reply = command_raw(&mut socket, b"NOOP\r\n").await;
+                assert_eq!(reply, "250 2.0.0 Ok\r\n");
+                let reply = command_raw(&mut socket, b"VRFY fred\r\n").await;
+                assert_eq!(
+                    reply,
+                    "250 2.0.0 Fred Smith <fred@example.com>\r\n"
+                );
+                command_raw(&mut socket, b"EHLO [127.0.0.1]\r\n").await;
+                let reply = command_raw(&mut socket, b"VRFY jane\r\n").await;
+                assert!(reply.starts_with("550 5.1.1 "), "{reply:?}");
+                let reply = command_raw(&mut socket, b"EXPN staff\r\n").await;
+                assert_eq!(
+                    reply,
+                    "250 2.0.0 Fred Smith <fred@example.com>\r\n"
+                );
+                let reply = command_raw(&mut socket, b"HELP\r\n").await;
+                assert!(reply.starts_with("214-2.0.0 "), "{reply:?}");
+                let reply = command_raw(
+                    &mut socket,
+                    b"MAIL FROM:<sender@example.com>\r\n",
+                )
+                .await;
+                assert!(reply.starts_with("250 "), "{reply:?}");
+                let reply = command_raw(&mut socket, b"NOOP\r\n").await;
+                assert_eq!(reply, "250 2.0.0 Ok\r\n");
+                let reply = command_raw(&mut socket, b"QUIT\r\n").await;
+                assert!(reply.starts_with("221 "), "{reply:?}");
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lmtp() {
+        use crate::{EnhancedCode, Protocol, Reply};
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        server.set_protocol(Protocol::Lmtp);
+        server.fail_delivery(
+            "Full@Example.com",
+            Reply::new(452, Some(EnhancedCode::new(4, 2, 2)), "Mailbox full"),
+        );
+        let address = server.address().unwrap();
+        tokio::join!(
+            async move {
+                let mut message = build_message(
+                    ("Sender", "sender@example.com"),
+                    ("Recipient", "recipient@example.com"),
+                    "Hello world",
+                    "Welcome",
+                    "<p>Welcome</p>",
+                )
+                .formatted();
+                message.extend(b"\r\n.\r\n");
+                let mut socket = connect_raw(address).await;
+                let reply =
+                    command_raw(&mut socket, b"LHLO client.example.com\r\n")
+                        .await;
+                assert!(reply.starts_with("250-"), "{reply:?}");
+                for command in [
+                    "MAIL FROM:<sender@example.com>\r\n",
+                    "RCPT TO:<recipient@example.com>\r\n",
+                    "RCPT TO:<full@example.com>\r\n",
+                    "DATA\r\n",
+                ] {
+                    let reply =
+                        command_raw(&mut socket, command.as_bytes()).await;
+                    assert!(
+                        reply.starts_with('2') || reply.starts_with('3'),
+                        "{reply:?}"
+                    );
+                }
+                let reply = command_raw(&mut socket, &message).await;
+                assert_eq!(reply, "250 2.0.0 Ok\r\n");
+                let reply = read_reply(&mut socket).await;
+                assert_eq!(reply, "452 4.2.2 Mailbox full\r\n");
+                let reply = command_raw(&mut socket, b"QUIT\r\n").await;
+                assert!(reply.starts_with("221 "), "{reply:?}");
+            },
+            async move {
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                assert_eq!(&email.helo_name, "client.example.com");
+                assert_eq!(email.recipients.len(), 1);
+                assert_eq!(&email.address_to, "recipient@example.com");
+                assert_eq!(&email.body_text, "Welcome\r\n");
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lmtp_failed_to_recipient() {
+        use crate::{Protocol, Reply};
+        let mut server = start_server(Auth::AcceptAnonOnly).await;
+        server.set_protocol(Protocol::Lmtp);
+        server.fail_delivery(
+            "recipient@example.com",
+            Reply::new(550, None, "No such user"),
+        );
+        let address = server.address().unwrap();
+        tokio::join!(
+            async move {
+                let mut message = lettre::Message::builder()
+                    .from("Sender <sender@example.com>".parse().unwrap())
+                    .to("Recipient <recipient@example.com>".parse().unwrap())
+                    .cc("Other <other@example.com>".parse().unwrap())
+                    .subject("Hello world")
+                    .body_text_and_html(
+                        "Welcome".to_string(),
+                        "<p>Welcome</p>".to_string(),
+                    )
+                    .unwrap()
+                    .formatted();
+                message.extend(b"\r\n.\r\n");
+                let mut socket = connect_raw(address).await;
+                let reply =
+                    command_raw(&mut socket, b"LHLO client.example.com\r\n")
+                        .await;
+                assert!(!reply.contains("AUTH"), "{reply:?}");
+                for command in [
+                    "MAIL FROM:<sender@example.com>\r\n",
+                    "RCPT TO:<recipient@example.com>\r\n",
+                    "RCPT TO:<other@example.com>\r\n",
+                    "DATA\r\n",
+                ] {
+                    let reply =
+                        command_raw(&mut socket, command.as_bytes()).await;
+                    assert!(
+                        reply.starts_with('2') || reply.starts_with('3'),
+                        "{reply:?}"
+                    );
+                }
+                let reply = command_raw(&mut socket, &message).await;
+                assert_eq!(reply, "550 No such user\r\n");
+                let reply = read_reply(&mut socket).await;
+                assert_eq!(reply, "250 2.0.0 Ok\r\n");
+                let reply = command_raw(&mut socket, b"QUIT\r\n").await;
+                assert!(reply.starts_with("221 "), "{reply:?}");
+            },
+            async move {
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                assert_eq!(email.recipients.len(), 1);
+                assert_eq!(&email.address_to, "other@example.com");
+            },
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use crate::Protocol;
+        use tokio::net::UnixStream;
+        let path = std::env::temp_dir()
+            .join(format!("smtp-test-server-{}.sock", std::process::id()));
+        let mut server = Server::start_unix(&path, Auth::AcceptAnonOnly)
+            .await
+            .unwrap();
+        server.set_protocol(Protocol::Lmtp);
+        assert_eq!(server.path(), Some(path.as_path()));
+        assert!(server.address().is_err());
+        let client_path = path.clone();
+        tokio::join!(
+            async move {
+                let socket =
+                    timeout("connecting", UnixStream::connect(client_path))
+                        .await
+                        .expect("error connecting");
+                let mut socket = BufReader::new(socket);
+                let greeting = read_reply(&mut socket).await;
+                assert_eq!(greeting, "220 localhost\r\n");
+                let reply =
+                    command_raw(&mut socket, b"LHLO client.example.com\r\n")
+                        .await;
+                assert!(reply.starts_with("250-localhost\r\n"), "{reply:?}");
+                for command in [
+                    "MAIL FROM:<sender@example.com>\r\n",
+                    "RCPT TO:<recipient@example.com>\r\n",
+                    "DATA\r\n",
+                ] {
+                    let reply =
+                        command_raw(&mut socket, command.as_bytes()).await;
+                    assert!(
+                        reply.starts_with('2') || reply.starts_with('3'),
+                        "{reply:?}"
+                    );
+                }
+                let mut message = build_message(
+                    ("Sender", "sender@example.com"),
+                    ("Recipient", "recipient@example.com"),
+                    "Hello",
+                    "Welcome",
+                    "<p>Welcome</p>",
+                )
+                .formatted();
+                message.extend(b"\r\n.\r\n");
+                let reply = command_raw(&mut socket, &message).await;
+                assert_eq!(reply, "250 2.0.0 Ok\r\n");
+                let reply = command_raw(&mut socket, b"QUIT\r\n").await;
+                assert!(reply.starts_with("221 "), "{reply:?}");
+            },
+            async {
+                let email = timeout("receiving email", server.try_receive())
+                    .await
+                    .expect("error receiving email");
+                assert_eq!(&email.address_to, "recipient@example.com");
+                assert_eq!(&email.subject, "Hello");
+            },
+        );
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_transport() {
+        use lettre::AsyncTransport;
+        let mut server = start_server(Auth::Login {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+        })
+        .await;
+        server.reject_recipient("rejected@example.com");
+        let transport = server.transport();
+
+        let message = build_message(
+            ("Sender", "sender@example.com"),
+            ("Recipient", "recipient@example.com"),
+            "Hello world",
+            ".Welcome",
+            "<p>Welcome</p>",
+        );
+        let reply = timeout("sending email", transport.send(message))
+            .await
+            .expect("error sending email");
+        assert_eq!(reply.to_string(), "250 2.0.0 Ok\r\n");
+        let email = timeout("receiving email", server.try_receive())
+            .await
+            .expect("error receiving email");
+        assert_eq!(&email.address_to, "recipient@example.com");
+        assert_eq!(&email.body_text, ".Welcome\r\n");
+
+        let message = build_message(
+            ("Sender", "sender@example.com"),
+            ("Recipient", "rejected@example.com"),
+            "Hello world",
+            "Welcome",
+            "<p>Welcome</p>",
+        );
+        let error = timeout("sending email", transport.send(message))
+            .await
+            .expect_err("sending to rejected recipient");
+        let crate::TransportError::Reply(reply) = error else {
+            panic!("unexpected error: {error:?}");
+        };
+        assert_eq!(reply.code, 550);
+
+        let blocking_transport = transport.clone();
+        let sent = tokio::task::spawn_blocking(move || {
+            let message = build_message(
+                ("Sender", "sender@example.com"),
+                ("Recipient", "blocking@example.com"),
+                "Hello world",
+                "Welcome",
+                "<p>Welcome</p>",
+            );
+            lettre::Transport::send(&blocking_transport, &message)
+        });
+        let email = timeout("receiving email", server.try_receive())
+            .await
+            .expect("error receiving email");
+        assert_eq!(&email.address_to, "blocking@example.com");
+        timeout("sending email", sent)
+            .await
+            .unwrap()
+            .expect("error sending email");
+    }
 }
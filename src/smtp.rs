@@ -1,8 +1,36 @@
-use std::net::IpAddr;
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    },
+    sync::mpsc,
+};
 
-const READ_WAIT: std::time::Duration = std::time::Duration::from_millis(10);
+use crate::{
+    bounce::{Bounce, BounceDelivery},
+    directory::Directory,
+    envelope::{
+        parse_mail_parameters, parse_rcpt_parameters, DsnReturn,
+        MailParameters, Recipient,
+    },
+    greylist::Greylist,
+    limits::{ConnectionSlot, Limiter},
+    reply::{EnhancedCode, Reply},
+    rules::{self, Rule},
+    spf::{Spf, SpfResult},
+    transcript::Transcripts,
+};
+
+const OK: EnhancedCode = EnhancedCode::new(2, 0, 0);
+const SENDER_OK: EnhancedCode = EnhancedCode::new(2, 1, 0);
+const RECIPIENT_OK: EnhancedCode = EnhancedCode::new(2, 1, 5);
+const AUTH_OK: EnhancedCode = EnhancedCode::new(2, 7, 0);
+const AUTH_FAILED: EnhancedCode = EnhancedCode::new(5, 7, 8);
+const NO_VALID_RECIPIENTS: EnhancedCode = EnhancedCode::new(5, 5, 1);
+const AUTH_REQUIRED: EnhancedCode = EnhancedCode::new(5, 7, 0);
+const INVALID_ARGUMENT: EnhancedCode = EnhancedCode::new(5, 5, 4);
+const NOT_IMPLEMENTED: EnhancedCode = EnhancedCode::new(5, 5, 1);
 
 /// An error during an SMTP exchange.
 #[derive(thiserror::Error, Debug)]
@@ -28,48 +56,264 @@ pub enum Auth {
     AcceptAll,
 }
 
+/// The protocol spoken by a server.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Protocol {
+    /// The Simple Mail Transfer Protocol (RFC 5321).
+    Smtp,
+    /// The Local Mail Transfer Protocol (RFC 2033).
+    ///
+    /// Clients greet with `LHLO` and the server replies
+    /// once per recipient after the message content.
+    Lmtp,
+}
+
+/// The behaviour of a SMTP server,
+/// shared by all connections it accepts.
+#[derive(Clone)]
+pub(crate) struct Settings {
+    pub auth: Auth,
+    pub protocol: Protocol,
+    /// The hostname in the greeting and `EHLO` reply,
+    /// instead of the server address.
+    pub hostname: Option<String>,
+    /// The text following the hostname in the greeting.
+    pub banner: Option<String>,
+    /// The capabilities advertised in the `EHLO` reply,
+    /// instead of those implied by the other settings.
+    pub capabilities: Option<Vec<String>>,
+    /// Checks the name given by the client in `EHLO` or `HELO`.
+    pub helo_validator: Option<HeloValidator>,
+    /// The users and mailing lists for `VRFY` and `EXPN`.
+    pub directory: Directory,
+    /// Advertise and accept the `CHUNKING` extension (RFC 3030).
+    pub chunking: bool,
+    /// The rules deciding which senders and recipients are accepted.
+    pub rules: Vec<Rule>,
+    /// The SPF policy, if enabled.
+    pub spf: Option<Spf>,
+    /// The greylisting policy and delivery attempts seen, if enabled.
+    pub greylist: Option<Greylist>,
+    /// The limits and the connections and messages counted towards them.
+    pub limiter: Limiter,
+    /// The transcripts of all connections.
+    pub transcripts: Transcripts,
+    /// Mask credentials in transcripts.
+    pub mask_secrets: bool,
+    /// The recipient addresses that fail delivery in LMTP mode,
+    /// with the reply sent after the message content.
+    pub delivery_failures: Vec<(String, Reply)>,
+    /// Where to deliver bounces for rejected recipients.
+    ///
+    /// If `None`, rejected recipients are refused at `RCPT TO`.
+    /// Otherwise permanently rejected recipients are accepted
+    /// and bounced once the message has been received.
+    pub bounces: Option<BounceDelivery>,
+    /// The address to send bounces to,
+    /// instead of the envelope sender.
+    pub bounce_return_path: Option<String>,
+    pub bounce_channel: mpsc::UnboundedSender<Bounce>,
+    /// Add `Received` and `Authentication-Results` headers to messages.
+    pub trace_headers: bool,
+    /// The client IP addresses allowed to send for each sender domain,
+    /// reported in the `Authentication-Results` header.
+    pub sender_ips: HashMap<String, Vec<IpAddr>>,
+    /// The public keys to verify DKIM signatures with.
+    #[cfg(feature = "dkim")]
+    pub dkim_keys: crate::DkimKeys,
+}
+
+impl Settings {
+    /// The name the server uses to identify itself.
+    pub fn hostname(&self, server_ip: Option<&IpAddr>) -> String {
+        match (&self.hostname, server_ip) {
+            (Some(hostname), _) => hostname.clone(),
+            (None, Some(server_ip)) => server_ip.to_string(),
+            (None, None) => "localhost".to_string(),
+        }
+    }
+
+    /// The reply to fail delivery to the recipient
+    /// with the given address in LMTP mode, if configured.
+    pub fn delivery_failure(&self, address: &str) -> Option<Reply> {
+        self.delivery_failures
+            .iter()
+            .find(|(failed, _)| failed.eq_ignore_ascii_case(address))
+            .map(|(_, reply)| reply.clone())
+    }
+
+    /// Whether the name given by the client in `EHLO` or `HELO` is accepted.
+    pub fn is_valid_helo(&self, name: &str) -> bool {
+        match &self.helo_validator {
+            Some(validator) => validator(name),
+            None => true,
+        }
+    }
+
+    /// The capabilities to advertise in the `EHLO` or `LHLO` reply.
+    pub fn capabilities(&self) -> Vec<String> {
+        if let Some(capabilities) = &self.capabilities {
+            return capabilities.clone();
+        }
+        let mut capabilities = Vec::new();
+        if self.chunking {
+            capabilities.push("CHUNKING".to_string());
+        }
+        capabilities.push("DSN".to_string());
+        capabilities.push("ENHANCEDSTATUSCODES".to_string());
+        // NOTE: LMTP clients do not authenticate, unless required
+        if self.protocol == Protocol::Smtp
+            || matches!(self.auth, Auth::Login { .. })
+        {
+            capabilities.push("AUTH PLAIN".to_string());
+        }
+        capabilities
+    }
+
+    /// The reply to refuse the envelope sender
+    /// with the given address, if any.
+    pub fn sender_rejection(&self, address: &str) -> Option<Reply> {
+        rules::check_sender(&self.rules, address)
+    }
+
+    /// The reply to reject the recipient with the given address,
+    /// if it cannot be delivered to.
+    pub fn rejection(&self, address: &str) -> Option<Reply> {
+        rules::check_recipient(&self.rules, address)
+    }
+
+    /// The reply to refuse the recipient with the given address
+    /// during the SMTP exchange, if any.
+    ///
+    /// With bounces enabled, permanently rejected recipients
+    /// are accepted and bounced instead.
+    pub fn recipient_refusal(&self, address: &str) -> Option<Reply> {
+        self.rejection(address)
+            .filter(|reply| self.bounces.is_none() || !reply.is_permanent())
+    }
+
+    /// Whether the recipient with the given address is accepted
+    /// only to be bounced once the message has been received.
+    pub fn is_bounced(&self, address: &str) -> bool {
+        self.bounces.is_some()
+            && self
+                .rejection(address)
+                .is_some_and(|reply| reply.is_permanent())
+    }
+}
+
+/// A function that checks the name given by the client
+/// in the `EHLO` or `HELO` command.
+pub type HeloValidator = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
 #[derive(Debug)]
 pub(crate) enum Response<T> {
     Email(T),
-    Continue,
     Quit,
 }
 
 #[derive(Debug)]
 pub(crate) struct Data {
     pub email: Vec<u8>,
+    pub helo_name: String,
+    /// Whether the client greeted with `EHLO` or `LHLO` instead of `HELO`.
+    pub extended: bool,
+    pub client_ip: Option<IpAddr>,
+    pub authenticated: bool,
+    pub user: Option<String>,
+    /// The result of the SPF check at `MAIL FROM`, if enabled.
+    pub spf: Option<SpfResult>,
     pub address_from: String,
-    pub address_to: String,
+    pub dsn_return: Option<DsnReturn>,
+    pub dsn_envelope_id: Option<String>,
+    pub mail_parameters: Vec<(String, Option<String>)>,
+    /// The recipients the message is delivered to.
+    pub recipients: Vec<Recipient>,
+    /// The recipients accepted only to be bounced,
+    /// see [`Settings::bounces`].
+    pub bounced: Vec<Recipient>,
 }
 
-/// Read up to a "/r/n".
+/// Read up to a "\r\n".
 async fn read(
-    mut socket: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    mut socket: impl AsyncBufRead + AsyncWrite + Unpin,
 ) -> Result<String, Error> {
-    let mut buffer = Vec::with_capacity(128 * 1024);
+    let mut buffer = Vec::with_capacity(1024);
+    let len = socket.read_until(b'\n', &mut buffer).await?;
+    if len == 0 {
+        return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    let data = String::from_utf8_lossy(&buffer).to_string();
+    #[cfg(feature = "tracing")]
+    {
+        use tracing::{event, Level};
+        event!(Level::TRACE, recv = data);
+    }
+    Ok(data)
+}
+
+/// Read the message content following a `DATA` command,
+/// up to the terminating ".\r\n" line.
+///
+/// Any dot-stuffing is removed from the returned content.
+async fn read_data(
+    mut socket: impl AsyncBufRead + AsyncWrite + Unpin,
+) -> Result<Vec<u8>, Error> {
+    let mut email = Vec::with_capacity(128 * 1024);
     loop {
-        let len = socket.read_buf(&mut buffer).await?;
+        let start = email.len();
+        let len = socket.read_until(b'\n', &mut email).await?;
         if len == 0 {
-            let _ = tokio::time::sleep(READ_WAIT).await;
-            continue;
+            return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
         }
-        if !buffer.is_empty() {
-            break;
+        match &email[start..] {
+            b".\r\n" => {
+                email.truncate(start);
+                break;
+            }
+            [b'.', ..] => {
+                email.remove(start);
+            }
+            _ => {}
         }
     }
-    let data = String::from_utf8_lossy(&buffer).to_string();
     #[cfg(feature = "tracing")]
     {
         use tracing::{event, Level};
-        event!(Level::TRACE, recv = data);
+        event!(Level::TRACE, recv = %String::from_utf8_lossy(&email));
     }
-    Ok(data)
+    Ok(email)
+}
+
+/// Read exactly `size` bytes of message content
+/// following a `BDAT` command.
+async fn read_chunk(
+    mut socket: impl AsyncBufRead + AsyncWrite + Unpin,
+    size: usize,
+) -> Result<Vec<u8>, Error> {
+    // NOTE: the size is given by the client,
+    //       so only allocate what is actually received
+    let mut chunk = Vec::new();
+    (&mut socket)
+        .take(size as u64)
+        .read_to_end(&mut chunk)
+        .await?;
+    if chunk.len() != size {
+        return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    #[cfg(feature = "tracing")]
+    {
+        use tracing::{event, Level};
+        event!(Level::TRACE, recv = %String::from_utf8_lossy(&chunk));
+    }
+    Ok(chunk)
 }
 
 async fn write(
-    mut socket: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
-    data: &str,
+    mut socket: impl AsyncBufRead + AsyncWrite + Unpin,
+    reply: &Reply,
 ) -> Result<(), Error> {
+    let data = reply.to_string();
     #[cfg(feature = "tracing")]
     {
         use tracing::{event, Level};
@@ -80,12 +324,55 @@ async fn write(
     Ok(())
 }
 
+/// Read the next command,
+/// answering any `NOOP`, `HELP`, `VRFY` and `EXPN` commands before it,
+/// and any `BDAT` commands if chunking is disabled.
+async fn read_command(
+    mut socket: impl AsyncBufRead + AsyncWrite + Unpin,
+    settings: &Settings,
+) -> Result<String, Error> {
+    loop {
+        let data = read(&mut socket).await?;
+        let line = data.trim_end_matches("\r\n");
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let reply = match command.to_ascii_uppercase().as_str() {
+            "NOOP" => Reply::new(250, Some(OK), "Ok"),
+            "HELP" => Reply::new(
+                214,
+                Some(OK),
+                match settings.protocol {
+                    Protocol::Smtp => {
+                        "Supported commands:\n\
+                         EHLO HELO AUTH MAIL RCPT DATA BDAT\n\
+                         RSET NOOP VRFY EXPN HELP QUIT"
+                    }
+                    Protocol::Lmtp => {
+                        "Supported commands:\n\
+                         LHLO AUTH MAIL RCPT DATA BDAT\n\
+                         RSET NOOP VRFY EXPN HELP QUIT"
+                    }
+                },
+            ),
+            "VRFY" => settings.directory.verify(argument.trim()),
+            "EXPN" => settings.directory.expand(argument.trim()),
+            "BDAT" if !settings.chunking => Reply::new(
+                502,
+                Some(NOT_IMPLEMENTED),
+                "Command not implemented",
+            ),
+            _ => return Ok(data),
+        };
+        write(&mut socket, &reply).await?;
+    }
+}
+
 async fn read_expect(
-    socket: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    mut socket: impl AsyncBufRead + AsyncWrite + Unpin,
+    settings: &Settings,
     expected: impl ToString,
 ) -> Result<(), Error> {
     let expected = expected.to_string();
-    let data = read(socket).await?;
+    let data = read_command(&mut socket, settings).await?;
     if data == expected {
         Ok(())
     } else {
@@ -96,22 +383,99 @@ async fn read_expect(
     }
 }
 
+/// Parse an `EHLO`, `HELO` or `LHLO` command into whether it was extended
+/// and the domain or address literal identifying the client.
+///
+/// In LMTP mode only `LHLO` is accepted,
+/// otherwise only `EHLO` and `HELO`.
+fn expect_hello(
+    data: String,
+    protocol: Protocol,
+) -> Result<(bool, String), Error> {
+    let parsed = data
+        .strip_suffix("\r\n")
+        .and_then(|line| line.split_once(' '))
+        .and_then(|(command, name)| {
+            let name = name.trim();
+            if name.is_empty() || name.contains(' ') {
+                return None;
+            }
+            let extended = match protocol {
+                Protocol::Smtp if command.eq_ignore_ascii_case("EHLO") => true,
+                Protocol::Smtp if command.eq_ignore_ascii_case("HELO") => false,
+                Protocol::Lmtp if command.eq_ignore_ascii_case("LHLO") => true,
+                _ => return None,
+            };
+            Some((extended, name.to_string()))
+        });
+    parsed.ok_or(Error::UnexpectedData {
+        expected: match protocol {
+            Protocol::Smtp => "EHLO <domain>\r\n",
+            Protocol::Lmtp => "LHLO <domain>\r\n",
+        }
+        .to_string(),
+        actual: data,
+    })
+}
+
+/// Parse a `MAIL FROM` or `RCPT TO` command
+/// into the address and any following parameters.
 fn expect_address(
     data: String,
     command: &'static str,
-) -> Result<String, Error> {
-    if data.starts_with(&format!("{command}:<")) && data.ends_with(">\r\n") {
-        let part = &data[(command.len() + 2)..(data.len() - 3)];
-        Ok(part.to_string())
-    } else {
-        Err(Error::UnexpectedData {
-            expected: format!("{command}:<...>\r\n"),
-            actual: data,
+) -> Result<(String, String), Error> {
+    let parsed = data
+        .strip_prefix(command)
+        .and_then(|rest| rest.strip_prefix(":<"))
+        .and_then(|rest| rest.strip_suffix("\r\n"))
+        .and_then(|rest| rest.split_once('>'))
+        .filter(|(_, params)| params.is_empty() || params.starts_with(' '))
+        .map(|(address, params)| (address.to_string(), params.to_string()));
+    parsed.ok_or(Error::UnexpectedData {
+        expected: format!("{command}:<...>\r\n"),
+        actual: data,
+    })
+}
+
+/// Parse a `MAIL FROM` command into the address and its parameters,
+/// which are `None` if invalid.
+fn expect_mail_from(
+    data: String,
+) -> Result<(String, Option<MailParameters>), Error> {
+    let (address, params) = expect_address(data, "MAIL FROM")?;
+    Ok((address, parse_mail_parameters(&params)))
+}
+
+/// Parse a `RCPT TO` command into the recipient,
+/// which is `None` if its parameters are invalid.
+fn expect_rcpt_to(data: String) -> Result<Option<Recipient>, Error> {
+    let (address, params) = expect_address(data, "RCPT TO")?;
+    Ok(parse_rcpt_parameters(address, &params))
+}
+
+fn invalid_parameters() -> Reply {
+    Reply::new(555, Some(INVALID_ARGUMENT), "Invalid parameters")
+}
+
+/// Parse a `BDAT <size> [LAST]` command
+/// into the chunk size and whether this is the last chunk.
+fn expect_bdat(data: String) -> Result<(usize, bool), Error> {
+    let parsed = data
+        .strip_prefix("BDAT ")
+        .and_then(|args| args.strip_suffix("\r\n"))
+        .and_then(|args| match args.split_once(' ') {
+            Some((size, "LAST")) => Some((size, true)),
+            Some(_) => None,
+            None => Some((args, false)),
         })
-    }
+        .and_then(|(size, last)| Some((size.parse().ok()?, last)));
+    parsed.ok_or(Error::UnexpectedData {
+        expected: "BDAT <size> [LAST]\r\n".to_string(),
+        actual: data,
+    })
 }
 
-fn encode_password(username: &str, password: &str) -> String {
+pub(crate) fn encode_password(username: &str, password: &str) -> String {
     use base64ct::Encoding;
     let mut data = Vec::with_capacity(2 + username.len() + password.len());
     data.push(0);
@@ -121,100 +485,412 @@ fn encode_password(username: &str, password: &str) -> String {
     base64ct::Base64::encode_string(&data)
 }
 
+/// Decode the user name from an `AUTH PLAIN` command, if valid.
+fn decode_username(data: &str) -> Option<String> {
+    use base64ct::Encoding;
+    let encoded = data.strip_prefix("AUTH PLAIN ")?.trim_end();
+    let decoded = base64ct::Base64::decode_vec(encoded).ok()?;
+    let mut parts = decoded.split(|&byte| byte == 0);
+    let (_authorization, username) = (parts.next()?, parts.next()?);
+    String::from_utf8(username.to_vec()).ok()
+}
+
 async fn respond_auth_ok(
-    mut socket: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    mut socket: impl AsyncBufRead + AsyncWrite + Unpin,
 ) -> Result<(), Error> {
-    write(&mut socket, "235 Authentication successful\r\n").await?;
+    write(
+        &mut socket,
+        &Reply::new(235, Some(AUTH_OK), "Authentication successful"),
+    )
+    .await?;
     Ok(())
 }
 
 async fn respond_auth_fail(
-    mut socket: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    mut socket: impl AsyncBufRead + AsyncWrite + Unpin,
+    settings: &Settings,
 ) -> Result<(), Error> {
-    write(&mut socket, "535 Authentication failed\r\n").await?;
+    write(
+        &mut socket,
+        &Reply::new(535, Some(AUTH_FAILED), "Authentication failed"),
+    )
+    .await?;
 
-    read_expect(&mut socket, "QUIT\r\n").await?;
-    write(&mut socket, "221 Ok\r\n").await?;
+    read_expect(&mut socket, settings, "QUIT\r\n").await?;
+    write(&mut socket, &Reply::new(221, Some(OK), "Ok")).await?;
     Ok(())
 }
 
+/// The state of a connection, kept across the messages sent over it.
+pub(crate) struct Session {
+    hostname: String,
+    client_ip: Option<IpAddr>,
+    /// The name given in `EHLO` or `HELO`, once greeted.
+    helo_name: Option<String>,
+    /// Whether the client greeted with `EHLO` or `LHLO`.
+    extended: bool,
+    authenticated: bool,
+    /// The user name given in `AUTH`, if any.
+    user: Option<String>,
+    /// The number of messages received so far.
+    messages: usize,
+    /// A command read after the previous message.
+    pending: Option<String>,
+    closed: bool,
+    _slot: Option<ConnectionSlot>,
+}
+
+impl Session {
+    pub fn new(hostname: String, client_ip: Option<IpAddr>) -> Self {
+        Self {
+            hostname,
+            client_ip,
+            helo_name: None,
+            extended: false,
+            authenticated: false,
+            user: None,
+            messages: 0,
+            pending: None,
+            closed: false,
+            _slot: None,
+        }
+    }
+}
+
+/// Receive the next message over the connection.
+///
+/// Returns [`Response::Quit`] once the connection is closed.
 pub(crate) async fn receive(
-    mut socket: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
-    server_ip: &IpAddr,
-    client_ip: &IpAddr,
-    auth: &Auth,
+    mut socket: impl AsyncBufRead + AsyncWrite + Unpin,
+    settings: &Settings,
+    session: &mut Session,
 ) -> Result<Response<Data>, Error> {
-    write(&mut socket, &format!("220 {server_ip}\r\n")).await?;
-    read_expect(&mut socket, format!("EHLO [{client_ip}]\r\n")).await?;
-
-    write(&mut socket, &format!("250-{server_ip}\r\n")).await?;
-    write(&mut socket, "250 AUTH PLAIN\r\n").await?;
-
-    let mut data = read(&mut socket).await?;
-    if data.starts_with("AUTH") {
-        match auth {
-            Auth::Login { username, password } => {
-                let auth = encode_password(username, password);
-                if data == format!("AUTH PLAIN {auth}\r\n") {
-                    respond_auth_ok(&mut socket).await?;
-                } else {
-                    respond_auth_fail(&mut socket).await?;
-                    return Ok(Response::Quit);
-                }
-            }
-            Auth::AcceptAnonOnly => {
-                respond_auth_fail(&mut socket).await?;
-                return Ok(Response::Quit);
+    if session.closed {
+        return Ok(Response::Quit);
+    }
+    if session.helo_name.is_none()
+        && !greet(&mut socket, settings, session).await?
+    {
+        session.closed = true;
+        return Ok(Response::Quit);
+    }
+    loop {
+        let data = match session.pending.take() {
+            Some(data) => data,
+            None => read_command(&mut socket, settings).await?,
+        };
+        if let Some(response) =
+            transaction(&mut socket, settings, session, data).await?
+        {
+            if matches!(response, Response::Quit) {
+                session.closed = true;
             }
-            Auth::AcceptAll => {
+            return Ok(response);
+        }
+    }
+}
+
+/// Greet the client and handle `EHLO` and `AUTH`.
+///
+/// Returns `false` if the connection was closed instead.
+async fn greet(
+    mut socket: impl AsyncBufRead + AsyncWrite + Unpin,
+    settings: &Settings,
+    session: &mut Session,
+) -> Result<bool, Error> {
+    match settings.limiter.connect() {
+        Ok(slot) => session._slot = Some(slot),
+        Err(reply) => {
+            write(&mut socket, &reply).await?;
+            return Ok(false);
+        }
+    }
+
+    let hostname = session.hostname.clone();
+    let greeting = match &settings.banner {
+        Some(banner) => format!("{hostname} {banner}"),
+        None => hostname.clone(),
+    };
+    write(&mut socket, &Reply::new(220, None, greeting)).await?;
+
+    let (extended, helo_name) = loop {
+        let data = read_command(&mut socket, settings).await?;
+        if data == "QUIT\r\n" {
+            write(&mut socket, &Reply::new(221, Some(OK), "Ok")).await?;
+            return Ok(false);
+        }
+        let (extended, helo_name) = expect_hello(data, settings.protocol)?;
+        if !settings.is_valid_helo(&helo_name) {
+            let reply = Reply::new(501, Some(INVALID_ARGUMENT), "Invalid name");
+            write(&mut socket, &reply).await?;
+            continue;
+        }
+        if extended {
+            let mut lines = vec![hostname];
+            lines.extend(settings.capabilities());
+            write(&mut socket, &Reply::new(250, None, lines.join("\n")))
+                .await?;
+        } else {
+            write(&mut socket, &Reply::new(250, None, hostname)).await?;
+        }
+        break (extended, helo_name);
+    };
+    (session.extended, session.helo_name) = (extended, Some(helo_name));
+
+    let data = read_command(&mut socket, settings).await?;
+    if !data.starts_with("AUTH") {
+        session.pending = Some(data);
+        return Ok(true);
+    }
+    match &settings.auth {
+        Auth::Login { username, password } => {
+            let auth = encode_password(username, password);
+            if data == format!("AUTH PLAIN {auth}\r\n") {
                 respond_auth_ok(&mut socket).await?;
+                session.user = Some(username.clone());
+            } else {
+                respond_auth_fail(&mut socket, settings).await?;
+                return Ok(false);
             }
         }
+        Auth::AcceptAnonOnly => {
+            respond_auth_fail(&mut socket, settings).await?;
+            return Ok(false);
+        }
+        Auth::AcceptAll => {
+            respond_auth_ok(&mut socket).await?;
+            session.user = decode_username(&data);
+        }
+    }
+    session.authenticated = true;
+    Ok(true)
+}
 
-        data = read(&mut socket).await?;
-    } else {
-        match auth {
-            Auth::Login { .. } => {
-                respond_auth_fail(socket).await?;
-                return Ok(Response::Quit);
+/// Handle a single mail transaction, starting with the given command.
+///
+/// Returns `None` if the transaction ended without a message
+/// and the connection remains open.
+async fn transaction(
+    mut socket: impl AsyncBufRead + AsyncWrite + Unpin,
+    settings: &Settings,
+    session: &mut Session,
+    data: String,
+) -> Result<Option<Response<Data>>, Error> {
+    if data == "QUIT\r\n" {
+        write(&mut socket, &Reply::new(221, Some(OK), "Ok")).await?;
+        return Ok(Some(Response::Quit));
+    } else if data == "RSET\r\n" {
+        write(&mut socket, &Reply::new(250, Some(OK), "Ok")).await?;
+        return Ok(None);
+    } else if !session.authenticated
+        && matches!(settings.auth, Auth::Login { .. })
+    {
+        write(
+            &mut socket,
+            &Reply::new(530, Some(AUTH_REQUIRED), "Authentication required"),
+        )
+        .await?;
+        read_expect(&mut socket, settings, "QUIT\r\n").await?;
+        write(&mut socket, &Reply::new(221, Some(OK), "Ok")).await?;
+        return Ok(Some(Response::Quit));
+    } else if !data.starts_with("MAIL") {
+        return Err(Error::UnexpectedContinuation { actual: data });
+    }
+
+    if let Some(reply) = settings.limiter.check_messages(session.messages) {
+        write(&mut socket, &reply).await?;
+        return Ok(Some(Response::Quit));
+    }
+    let (address_from, params) = expect_mail_from(data)?;
+    let Some(params) = params else {
+        write(&mut socket, &invalid_parameters()).await?;
+        return Ok(None);
+    };
+    let spf = settings.spf.as_ref().map(|policy| {
+        let domain = match address_from.rsplit_once('@') {
+            Some((_, domain)) => domain,
+            None => session.helo_name.as_deref().unwrap_or_default(),
+        };
+        let result = match session.client_ip {
+            Some(ip) => policy.check(ip, domain),
+            None => SpfResult::None,
+        };
+        (result, policy.rejection(result))
+    });
+    let refusal = settings
+        .sender_rejection(&address_from)
+        .or_else(|| spf.as_ref().and_then(|(_, reply)| reply.clone()))
+        .or_else(|| {
+            settings
+                .limiter
+                .check_rate(session.client_ip, session.user.as_deref())
+        });
+    if let Some(reply) = refusal {
+        // NOTE: the client may try again, or reset or quit,
+        //       which starts the next transaction
+        write(&mut socket, &reply).await?;
+        return Ok(None);
+    }
+    let spf = spf.map(|(result, _)| result);
+    write(&mut socket, &Reply::new(250, Some(SENDER_OK), "Ok")).await?;
+
+    let mut recipients = Vec::new();
+    let mut data = read_command(&mut socket, settings).await?;
+    while data.starts_with("RCPT") {
+        let Some(recipient) = expect_rcpt_to(data)? else {
+            write(&mut socket, &invalid_parameters()).await?;
+            data = read_command(&mut socket, settings).await?;
+            continue;
+        };
+        let refusal = settings
+            .limiter
+            .check_recipients(recipients.len())
+            .or_else(|| settings.recipient_refusal(&recipient.address))
+            .or_else(|| {
+                let greylist = settings.greylist.as_ref()?;
+                greylist.check(
+                    session.client_ip,
+                    &address_from,
+                    &recipient.address,
+                )
+            });
+        match refusal {
+            Some(reply) => {
+                write(&mut socket, &reply).await?;
+            }
+            None => {
+                recipients.push(recipient);
+                let reply = Reply::new(250, Some(RECIPIENT_OK), "Ok");
+                write(&mut socket, &reply).await?;
             }
-            Auth::AcceptAnonOnly | Auth::AcceptAll => {}
         }
+        data = read_command(&mut socket, settings).await?;
+    }
+    if data == "QUIT\r\n" {
+        write(&mut socket, &Reply::new(221, Some(OK), "Ok")).await?;
+        return Ok(Some(Response::Quit));
+    } else if data == "RSET\r\n" {
+        write(&mut socket, &Reply::new(250, Some(OK), "Ok")).await?;
+        return Ok(None);
+    }
+    if recipients.is_empty() {
+        write(
+            &mut socket,
+            &Reply::new(554, Some(NO_VALID_RECIPIENTS), "No valid recipients"),
+        )
+        .await?;
+        read_expect(&mut socket, settings, "QUIT\r\n").await?;
+        write(&mut socket, &Reply::new(221, Some(OK), "Ok")).await?;
+        return Ok(Some(Response::Quit));
     }
 
-    if data == "NOOP\r\n" {
-        write(&mut socket, "250 Ok\r\n").await?;
+    let email = if settings.chunking && data.starts_with("BDAT") {
+        let mut email = Vec::with_capacity(128 * 1024);
+        loop {
+            let (size, last) = expect_bdat(data)?;
+            email.extend(read_chunk(&mut socket, size).await?);
+            if last {
+                break email;
+            }
+            write(&mut socket, &Reply::new(250, Some(OK), "Ok")).await?;
+            data = read_command(&mut socket, settings).await?;
+        }
+    } else if data == "DATA\r\n" {
+        write(&mut socket, &Reply::new(354, None, "Go")).await?;
+        read_data(&mut socket).await?
+    } else {
+        return Err(Error::UnexpectedData {
+            expected: "DATA\r\n".to_string(),
+            actual: data,
+        });
+    };
 
-        read_expect(&mut socket, "QUIT\r\n").await?;
-        write(&mut socket, "221 Ok\r\n").await?;
+    settings
+        .limiter
+        .record_message(session.client_ip, session.user.as_deref());
 
-        Ok(Response::Continue)
-    } else if data.starts_with("MAIL") {
-        let address_from = expect_address(data, "MAIL FROM")?;
-        write(&mut socket, "250 Ok\r\n").await?;
+    let (bounced, delivered) = recipients
+        .iter()
+        .cloned()
+        .partition(|recipient| settings.is_bounced(&recipient.address));
+    let mut data = Data {
+        email,
+        helo_name: session.helo_name.clone().unwrap_or_default(),
+        extended: session.extended,
+        client_ip: session.client_ip,
+        authenticated: session.authenticated,
+        user: session.user.clone(),
+        spf,
+        address_from,
+        dsn_return: params.dsn_return,
+        dsn_envelope_id: params.dsn_envelope_id,
+        mail_parameters: params.other,
+        recipients: delivered,
+        bounced,
+    };
+    // NOTE: bounce before replying,
+    //       so the bounce is available once the client is done
+    crate::bounce::deliver(&data, settings, &session.hostname);
 
-        let data = read(&mut socket).await?;
-        let address_to = expect_address(data, "RCPT TO")?;
-        write(&mut socket, "250 Ok\r\n").await?;
+    match settings.protocol {
+        Protocol::Smtp => {
+            write(&mut socket, &Reply::new(250, Some(OK), "Ok")).await?;
+        }
+        Protocol::Lmtp => {
+            for recipient in recipients {
+                let failure = settings
+                    .delivery_failure(&recipient.address)
+                    .filter(|_| !settings.is_bounced(&recipient.address));
+                match failure {
+                    Some(reply) => {
+                        write(&mut socket, &reply).await?;
+                        data.recipients.retain(|delivered| {
+                            delivered.address != recipient.address
+                        });
+                    }
+                    None => {
+                        let reply = Reply::new(250, Some(OK), "Ok");
+                        write(&mut socket, &reply).await?;
+                    }
+                }
+            }
+        }
+    }
+    session.messages += 1;
 
-        read_expect(&mut socket, "DATA\r\n").await?;
-        write(&mut socket, "354 Go\r\n").await?;
+    // NOTE: the message is only handed over once the client
+    //       quits or starts the next transaction,
+    //       but is not lost if the client disconnects instead
+    match read_command(&mut socket, settings).await {
+        Ok(data) if data == "QUIT\r\n" => {
+            let reply = Reply::new(221, Some(OK), "Ok");
+            let _ = write(&mut socket, &reply).await;
+            session.closed = true;
+        }
+        Ok(data) => session.pending = Some(data),
+        Err(_) => session.closed = true,
+    }
 
-        let mut email = Vec::with_capacity(128 * 1024);
-        socket.read_buf(&mut email).await?;
+    if data.recipients.is_empty() {
+        return Ok(session.closed.then_some(Response::Quit));
+    }
+    Ok(Some(Response::Email(data)))
+}
 
-        read_expect(&mut socket, "\r\n.\r\n").await?;
-        write(&mut socket, "250 Ok\r\n").await?;
+#[cfg(test)]
+mod tests {
+    use tokio::io::BufReader;
 
-        read_expect(&mut socket, "QUIT\r\n").await?;
-        write(&mut socket, "221 Ok\r\n").await?;
+    use super::{read_chunk, Error};
 
-        Ok(Response::Email(Data {
-            email,
-            address_from,
-            address_to,
-        }))
-    } else {
-        Err(Error::UnexpectedContinuation { actual: data })
+    #[tokio::test]
+    async fn chunk_size() {
+        let mut socket = BufReader::new(
+            tokio_test::io::Builder::new().read(b"Hello").build(),
+        );
+        let chunk = read_chunk(&mut socket, 4).await.unwrap();
+        assert_eq!(chunk, b"Hell");
+        let result = read_chunk(&mut socket, usize::MAX).await;
+        assert!(matches!(result, Err(Error::Io(_))), "{result:?}");
     }
 }
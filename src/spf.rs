@@ -0,0 +1,291 @@
+use std::{collections::HashMap, fmt, net::IpAddr};
+
+use crate::reply::{EnhancedCode, Reply};
+
+const SPF_FAILED: EnhancedCode = EnhancedCode::new(5, 7, 23);
+
+/// The maximum number of terms causing DNS lookups, see RFC 7208.
+const MAX_LOOKUPS: usize = 10;
+
+/// The SPF policy of a server.
+///
+/// The client IP address is checked against the SPF record
+/// of the envelope sender domain at `MAIL FROM`,
+/// or of the `EHLO` name if the sender is empty.
+/// Records are looked up in a table instead of the DNS.
+///
+/// The `a`, `mx`, `ptr` and `exists` mechanisms never match,
+/// as the table holds no address records,
+/// but count towards the limit of 10 lookups.
+/// Macros are not supported and result in a permanent error.
+#[derive(Clone, Debug)]
+pub struct Spf {
+    /// The TXT records by domain name.
+    ///
+    /// Records not starting with `v=spf1` are ignored.
+    pub records: HashMap<String, String>,
+    /// Refuse senders failing the check with `550 5.7.23`.
+    pub reject_failures: bool,
+}
+
+/// The result of an SPF check, see RFC 7208.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SpfResult {
+    /// The domain has no SPF record.
+    None,
+    /// The domain makes no assertion about the client.
+    Neutral,
+    /// The client is allowed to send for the domain.
+    Pass,
+    /// The client is not allowed to send for the domain.
+    Fail,
+    /// The client is probably not allowed to send for the domain.
+    SoftFail,
+    /// The SPF record of the domain is invalid.
+    PermError,
+}
+
+impl fmt::Display for SpfResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Neutral => "neutral",
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+            Self::SoftFail => "softfail",
+            Self::PermError => "permerror",
+        })
+    }
+}
+
+impl Spf {
+    /// An SPF policy with the given TXT records by domain name,
+    /// which does not refuse any senders.
+    pub fn new<D, R>(records: impl IntoIterator<Item = (D, R)>) -> Self
+    where
+        D: Into<String>,
+        R: Into<String>,
+    {
+        Self {
+            records: records
+                .into_iter()
+                .map(|(domain, record)| (domain.into(), record.into()))
+                .collect(),
+            reject_failures: false,
+        }
+    }
+
+    /// Check whether the client IP address may send for the domain.
+    pub fn check(&self, client_ip: IpAddr, domain: &str) -> SpfResult {
+        Evaluation {
+            spf: self,
+            client_ip: client_ip.to_canonical(),
+            lookups: 0,
+        }
+        .check_host(domain)
+    }
+
+    /// The reply to refuse the sender with, if any.
+    pub(crate) fn rejection(&self, result: SpfResult) -> Option<Reply> {
+        (self.reject_failures && result == SpfResult::Fail)
+            .then(|| Reply::new(550, Some(SPF_FAILED), "SPF validation failed"))
+    }
+
+    fn record(&self, domain: &str) -> Option<&str> {
+        let domain = domain.trim_end_matches('.');
+        self.records
+            .iter()
+            .find(|(name, _)| {
+                name.trim_end_matches('.').eq_ignore_ascii_case(domain)
+            })
+            .map(|(_, record)| record.as_str())
+            .filter(|record| {
+                let version = record.split_ascii_whitespace().next();
+                version.is_some_and(|version| {
+                    version.eq_ignore_ascii_case("v=spf1")
+                })
+            })
+    }
+}
+
+struct Evaluation<'a> {
+    spf: &'a Spf,
+    client_ip: IpAddr,
+    lookups: usize,
+}
+
+impl Evaluation<'_> {
+    fn check_host(&mut self, domain: &str) -> SpfResult {
+        let Some(record) = self.spf.record(domain) else {
+            return SpfResult::None;
+        };
+        let mut redirect = None;
+        for term in record.split_ascii_whitespace().skip(1) {
+            if let Some((name, value)) = modifier(term) {
+                if name.eq_ignore_ascii_case("redirect") {
+                    if redirect.is_some() {
+                        return SpfResult::PermError;
+                    }
+                    redirect = Some(value);
+                }
+                // NOTE: unknown modifiers, and `exp`, are ignored
+                continue;
+            }
+            let qualifier = match term.chars().next() {
+                Some('+') => Some(SpfResult::Pass),
+                Some('-') => Some(SpfResult::Fail),
+                Some('~') => Some(SpfResult::SoftFail),
+                Some('?') => Some(SpfResult::Neutral),
+                _ => None,
+            };
+            let mechanism = match qualifier {
+                Some(_) => &term[1..],
+                None => term,
+            };
+            let qualifier = qualifier.unwrap_or(SpfResult::Pass);
+            match self.matches(mechanism) {
+                Ok(true) => return qualifier,
+                Ok(false) => {}
+                Err(()) => return SpfResult::PermError,
+            }
+        }
+        match redirect {
+            Some(target) => {
+                if !self.lookup() || target.contains('%') {
+                    return SpfResult::PermError;
+                }
+                match self.check_host(target) {
+                    SpfResult::None => SpfResult::PermError,
+                    result => result,
+                }
+            }
+            None => SpfResult::Neutral,
+        }
+    }
+
+    fn matches(&mut self, mechanism: &str) -> Result<bool, ()> {
+        let end = mechanism.find([':', '/']).unwrap_or(mechanism.len());
+        let (name, argument) = mechanism.split_at(end);
+        let argument = argument.strip_prefix(':').unwrap_or(argument);
+        if argument.contains('%') {
+            return Err(());
+        }
+        match name.to_ascii_lowercase().as_str() {
+            "all" if argument.is_empty() => Ok(true),
+            "ip4" => ip_matches(argument, self.client_ip, true),
+            "ip6" => ip_matches(argument, self.client_ip, false),
+            "include" if !argument.is_empty() => {
+                if !self.lookup() {
+                    return Err(());
+                }
+                match self.check_host(argument) {
+                    SpfResult::Pass => Ok(true),
+                    SpfResult::Fail
+                    | SpfResult::SoftFail
+                    | SpfResult::Neutral => Ok(false),
+                    SpfResult::None | SpfResult::PermError => Err(()),
+                }
+            }
+            "a" | "mx" | "ptr" | "exists" => {
+                if self.lookup() {
+                    Ok(false)
+                } else {
+                    Err(())
+                }
+            }
+            _ => Err(()),
+        }
+    }
+
+    /// Count a term causing a DNS lookup,
+    /// returning `false` if the limit was exceeded.
+    fn lookup(&mut self) -> bool {
+        self.lookups += 1;
+        self.lookups <= MAX_LOOKUPS
+    }
+}
+
+/// Split a modifier such as `redirect=example.com` into its name and value.
+fn modifier(term: &str) -> Option<(&str, &str)> {
+    let (name, value) = term.split_once('=')?;
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    valid.then_some((name, value))
+}
+
+/// Whether an `ip4` or `ip6` argument such as `192.0.2.0/24` matches.
+fn ip_matches(argument: &str, client_ip: IpAddr, v4: bool) -> Result<bool, ()> {
+    let (address, prefix) = match argument.split_once('/') {
+        Some((address, prefix)) => {
+            (address, Some(prefix.parse::<u32>().map_err(|_| ())?))
+        }
+        None => (argument, None),
+    };
+    let address = address.parse::<IpAddr>().map_err(|_| ())?;
+    match (address, client_ip) {
+        (IpAddr::V4(address), IpAddr::V4(client)) if v4 => {
+            let prefix = prefix.unwrap_or(32);
+            if prefix > 32 {
+                return Err(());
+            }
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            Ok(u32::from(address) & mask == u32::from(client) & mask)
+        }
+        (IpAddr::V6(address), IpAddr::V6(client)) if !v4 => {
+            let prefix = prefix.unwrap_or(128);
+            if prefix > 128 {
+                return Err(());
+            }
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            Ok(u128::from(address) & mask == u128::from(client) & mask)
+        }
+        (IpAddr::V4(_), _) if v4 => Ok(false),
+        (IpAddr::V6(_), _) if !v4 => Ok(false),
+        _ => Err(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Spf, SpfResult};
+
+    #[test]
+    fn check() {
+        let spf = Spf::new([
+            (
+                "example.com",
+                "v=spf1 ip4:192.0.2.0/24 include:_spf.example.net ~all",
+            ),
+            ("_spf.example.net", "v=spf1 ip6:2001:db8::/32 -all"),
+            ("redirected.example", "v=spf1 redirect=example.com"),
+            ("neutral.example", "v=spf1 a mx ?ip4:198.51.100.1"),
+            ("invalid.example", "v=spf1 ip4:192.0.2.300 -all"),
+            ("loop.example", "v=spf1 include:loop.example -all"),
+            ("other.example", "some verification token"),
+        ]);
+        let check = |ip: &str, domain| spf.check(ip.parse().unwrap(), domain);
+        assert_eq!(check("192.0.2.10", "example.com"), SpfResult::Pass);
+        assert_eq!(check("::ffff:192.0.2.10", "Example.COM."), SpfResult::Pass);
+        assert_eq!(check("2001:db8::1", "example.com"), SpfResult::Pass);
+        assert_eq!(check("203.0.113.1", "example.com"), SpfResult::SoftFail);
+        assert_eq!(check("2001:db9::1", "_spf.example.net"), SpfResult::Fail);
+        assert_eq!(
+            check("203.0.113.1", "redirected.example"),
+            SpfResult::SoftFail
+        );
+        assert_eq!(
+            check("198.51.100.1", "neutral.example"),
+            SpfResult::Neutral
+        );
+        assert_eq!(check("203.0.113.1", "neutral.example"), SpfResult::Neutral);
+        assert_eq!(
+            check("192.0.2.10", "invalid.example"),
+            SpfResult::PermError
+        );
+        assert_eq!(check("192.0.2.10", "loop.example"), SpfResult::PermError);
+        assert_eq!(check("192.0.2.10", "other.example"), SpfResult::None);
+        assert_eq!(check("192.0.2.10", "unknown.example"), SpfResult::None);
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::email::Email;
+
+/// A conversation of emails connected by their
+/// `Message-ID`, `In-Reply-To` and `References` headers.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Thread {
+    /// The emails in this thread, in the order they were received.
+    pub emails: Vec<Email>,
+}
+
+impl Thread {
+    /// The email that started this thread,
+    /// which is the first email that is not a reply.
+    ///
+    /// Returns `None` if the thread is an orphan,
+    /// see [`Thread::is_orphan`].
+    pub fn root(&self) -> Option<&Email> {
+        self.emails.iter().find(|email| {
+            email.in_reply_to().is_empty() && email.references().is_empty()
+        })
+    }
+
+    /// Whether the email that started this thread was never received,
+    /// so all emails in this thread are replies.
+    pub fn is_orphan(&self) -> bool {
+        self.root().is_none()
+    }
+
+    /// The message IDs referenced by emails in this thread
+    /// that do not belong to any received email, in order.
+    pub fn missing(&self) -> Vec<String> {
+        let known = self
+            .emails
+            .iter()
+            .filter_map(Email::message_id)
+            .collect::<HashSet<_>>();
+        let mut missing = Vec::new();
+        for email in &self.emails {
+            for id in email.references().into_iter().chain(email.in_reply_to())
+            {
+                if !known.contains(&id) && !missing.contains(&id) {
+                    missing.push(id);
+                }
+            }
+        }
+        missing
+    }
+}
+
+/// Group emails into threads, in the order their first email was received.
+///
+/// Emails are in the same thread if they share a message ID,
+/// either their own or one they reply to or reference.
+/// Emails without any message ID each form their own thread.
+pub(crate) fn threads(emails: &[Email]) -> Vec<Thread> {
+    let mut parents = (0..emails.len()).collect::<Vec<_>>();
+    let mut first_seen = HashMap::new();
+    for (index, email) in emails.iter().enumerate() {
+        let ids = email
+            .message_id()
+            .into_iter()
+            .chain(email.in_reply_to())
+            .chain(email.references());
+        for id in ids {
+            let other = *first_seen.entry(id).or_insert(index);
+            let (root, other_root) =
+                (find(&mut parents, index), find(&mut parents, other));
+            // keep the earliest email as the root of each group,
+            // so threads are ordered by their first email
+            parents[root.max(other_root)] = root.min(other_root);
+        }
+    }
+
+    let mut threads = Vec::<Thread>::new();
+    let mut positions = HashMap::new();
+    for (index, email) in emails.iter().enumerate() {
+        let root = find(&mut parents, index);
+        let position = *positions.entry(root).or_insert_with(|| {
+            threads.push(Thread { emails: Vec::new() });
+            threads.len() - 1
+        });
+        threads[position].emails.push(email.clone());
+    }
+    threads
+}
+
+fn find(parents: &mut [usize], mut index: usize) -> usize {
+    while parents[index] != index {
+        parents[index] = parents[parents[index]];
+        index = parents[index];
+    }
+    index
+}
@@ -0,0 +1,183 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    bounce::format_date,
+    smtp::{Data, Protocol, Settings},
+    spf::SpfResult,
+};
+
+/// Prepend the `Received` and `Authentication-Results` headers
+/// a receiving MTA would add to a message.
+pub(crate) fn prepend(
+    data: &mut Data,
+    settings: &Settings,
+    hostname: &str,
+    #[cfg(feature = "dkim")] dkim: &[crate::DkimSignature],
+) {
+    #[cfg(feature = "dkim")]
+    let dkim = dkim_results(dkim);
+    #[cfg(not(feature = "dkim"))]
+    let dkim = Vec::new();
+    let results = [auth_result(data), spf_result(data, settings)]
+        .into_iter()
+        .chain(dkim)
+        .collect::<Vec<_>>();
+    let mut headers = received(data, settings, hostname);
+    headers.push_str(&format!(
+        "Authentication-Results: {hostname};\r\n\t{}\r\n",
+        results.join(";\r\n\t")
+    ));
+    data.email.splice(0..0, headers.into_bytes());
+}
+
+fn received(data: &Data, settings: &Settings, hostname: &str) -> String {
+    let from = match data.client_ip {
+        Some(ip) => format!("{} ([{ip}])", data.helo_name),
+        None => data.helo_name.clone(),
+    };
+    // see RFC 3848 for the protocol types
+    let protocol = match (settings.protocol, data.extended) {
+        (Protocol::Lmtp, _) => "LMTP",
+        (Protocol::Smtp, true) => "ESMTP",
+        (Protocol::Smtp, false) => "SMTP",
+    };
+    let authenticated = if data.authenticated { "A" } else { "" };
+    let recipient = match data.recipients.as_slice() {
+        [recipient] => format!("\r\n\tfor <{}>", recipient.address),
+        _ => String::new(),
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!(
+        "Received: from {from}\r\n\
+         \tby {hostname} with {protocol}{authenticated}{recipient}; {}\r\n",
+        format_date(timestamp),
+    )
+}
+
+fn auth_result(data: &Data) -> String {
+    match (data.authenticated, &data.user) {
+        (true, Some(user)) => format!("auth=pass smtp.auth={}", pvalue(user)),
+        (true, None) => "auth=pass".to_string(),
+        (false, _) => "auth=none".to_string(),
+    }
+}
+
+/// Format a property value of a result,
+/// quoting it unless it is a simple name or address (RFC 8601).
+fn pvalue(value: &str) -> String {
+    let simple = !value.is_empty()
+        && value.matches('@').count() <= 1
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._+@".contains(c));
+    if simple {
+        return value.to_string();
+    }
+    quoted(value)
+}
+
+/// Format a value as a quoted-string (RFC 5322).
+fn quoted(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The result of the SPF check if enabled, otherwise
+/// check the client IP address against the addresses
+/// allowed to send for the domain of the envelope sender.
+fn spf_result(data: &Data, settings: &Settings) -> String {
+    let Some((_, domain)) = data.address_from.rsplit_once('@') else {
+        let result = data.spf.unwrap_or(SpfResult::None);
+        return format!("spf={result} smtp.helo={}", pvalue(&data.helo_name));
+    };
+    let result = match (
+        data.spf,
+        settings.sender_ips.get(&domain.to_ascii_lowercase()),
+    ) {
+        (Some(result), _) => result,
+        (None, None) => SpfResult::None,
+        (None, Some(ips))
+            if data.client_ip.is_some_and(|ip| ips.contains(&ip)) =>
+        {
+            SpfResult::Pass
+        }
+        (None, Some(_)) => SpfResult::Fail,
+    };
+    format!("spf={result} smtp.mailfrom={}", pvalue(&data.address_from))
+}
+
+#[cfg(feature = "dkim")]
+fn dkim_results(signatures: &[crate::DkimSignature]) -> Vec<String> {
+    use crate::{DkimFailure, DkimResult};
+    if signatures.is_empty() {
+        return vec!["dkim=none".to_string()];
+    }
+    signatures
+        .iter()
+        .map(|signature| {
+            let result = match &signature.result {
+                DkimResult::Pass => "dkim=pass".to_string(),
+                DkimResult::Fail(
+                    failure @ (DkimFailure::Expired
+                    | DkimFailure::BodyHashMismatch
+                    | DkimFailure::SignatureMismatch),
+                ) => {
+                    format!("dkim=fail reason={}", quoted(&failure.to_string()))
+                }
+                DkimResult::Fail(failure) => format!(
+                    "dkim=permerror reason={}",
+                    quoted(&failure.to_string())
+                ),
+            };
+            format!(
+                "{result} header.d={} header.s={}",
+                pvalue(&signature.domain),
+                pvalue(&signature.selector)
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pvalue;
+
+    #[test]
+    fn quoting() {
+        assert_eq!(pvalue("user"), "user");
+        assert_eq!(pvalue("user+tag@example.com"), "user+tag@example.com");
+        assert_eq!(pvalue("John Doe"), "\"John Doe\"");
+        assert_eq!(pvalue("a\"b\\c;d"), "\"a\\\"b\\\\c;d\"");
+        assert_eq!(pvalue(""), "\"\"");
+    }
+
+    #[cfg(feature = "dkim")]
+    #[test]
+    fn dkim_reason() {
+        use crate::{DkimFailure, DkimResult, DkimSignature};
+        let signature = DkimSignature {
+            domain: "example.com".to_string(),
+            selector: "default".to_string(),
+            result: DkimResult::Fail(DkimFailure::Malformed(
+                "bad tag \"x\\\"".to_string(),
+            )),
+        };
+        assert_eq!(
+            super::dkim_results(&[signature]),
+            ["dkim=permerror \
+              reason=\"malformed signature: bad tag \\\"x\\\\\\\"\" \
+              header.d=example.com header.s=default"]
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    fmt,
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A record of all lines exchanged over a single connection.
+///
+/// Its display format lists each line with the time since
+/// the connection was accepted, prefixed by `C:` for lines
+/// sent by the client and `S:` for lines sent by the server.
+#[derive(Clone, Default, Debug)]
+#[non_exhaustive]
+pub struct Transcript {
+    /// The IP address of the client, if connected over TCP.
+    pub client_ip: Option<IpAddr>,
+
+    /// When the connection was accepted.
+    pub started: Option<SystemTime>,
+
+    /// All lines exchanged so far, in order.
+    pub lines: Vec<TranscriptLine>,
+}
+
+/// A single line in a [`Transcript`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct TranscriptLine {
+    /// When the line was sent or received.
+    pub time: SystemTime,
+
+    /// Who sent the line.
+    pub direction: Direction,
+
+    /// The line, without the terminating "\r\n".
+    pub line: String,
+}
+
+/// Who sent a [`TranscriptLine`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    /// Sent by the client and received by the server.
+    Received,
+    /// Sent by the server.
+    Sent,
+}
+
+impl fmt::Display for Transcript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let started = self
+            .started
+            .or_else(|| self.lines.first().map(|line| line.time));
+        for line in &self.lines {
+            let elapsed = started
+                .and_then(|started| line.time.duration_since(started).ok())
+                .unwrap_or(Duration::ZERO);
+            let prefix = match line.direction {
+                Direction::Received => "C",
+                Direction::Sent => "S",
+            };
+            writeln!(
+                f,
+                "+{}.{:03}s {prefix}: {}",
+                elapsed.as_secs(),
+                elapsed.subsec_millis(),
+                line.line
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Records the transcript of a connection, shared with the server.
+#[derive(Clone, Debug)]
+pub(crate) struct Recorder {
+    transcript: Arc<Mutex<Transcript>>,
+    mask_secrets: bool,
+}
+
+impl Recorder {
+    pub fn new(client_ip: Option<IpAddr>, mask_secrets: bool) -> Self {
+        Self {
+            transcript: Arc::new(Mutex::new(Transcript {
+                client_ip,
+                started: Some(SystemTime::now()),
+                lines: Vec::new(),
+            })),
+            mask_secrets,
+        }
+    }
+
+    /// A copy of the transcript so far.
+    pub fn transcript(&self) -> Transcript {
+        self.transcript.lock().expect("transcript poisoned").clone()
+    }
+
+    fn record(&self, direction: Direction, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches(['\r', '\n']);
+        let line = match direction {
+            Direction::Received if self.mask_secrets => mask_secrets(line),
+            _ => line.to_string(),
+        };
+        self.transcript
+            .lock()
+            .expect("transcript poisoned")
+            .lines
+            .push(TranscriptLine {
+                time: SystemTime::now(),
+                direction,
+                line,
+            });
+    }
+}
+
+/// Replace the credentials in an `AUTH` command.
+fn mask_secrets(line: &str) -> String {
+    let mut parts = line.splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(command), Some(mechanism), Some(_))
+            if command.eq_ignore_ascii_case("AUTH") =>
+        {
+            format!("{command} {mechanism} ****")
+        }
+        _ => line.to_string(),
+    }
+}
+
+/// The transcripts of all connections accepted by a server.
+#[derive(Clone, Default, Debug)]
+pub(crate) struct Transcripts {
+    recorders: Arc<Mutex<Vec<Recorder>>>,
+}
+
+impl Transcripts {
+    /// Start recording a new connection.
+    pub fn start(
+        &self,
+        client_ip: Option<IpAddr>,
+        mask_secrets: bool,
+    ) -> Recorder {
+        let recorder = Recorder::new(client_ip, mask_secrets);
+        self.recorders
+            .lock()
+            .expect("transcripts poisoned")
+            .push(recorder.clone());
+        recorder
+    }
+
+    /// A copy of the transcripts of all connections so far.
+    pub fn all(&self) -> Vec<Transcript> {
+        self.recorders
+            .lock()
+            .expect("transcripts poisoned")
+            .iter()
+            .map(Recorder::transcript)
+            .collect()
+    }
+}
+
+/// A stream that records all complete lines passing through it.
+pub(crate) struct Recorded<S> {
+    inner: S,
+    recorder: Recorder,
+    received: Vec<u8>,
+    sent: Vec<u8>,
+}
+
+impl<S> Recorded<S> {
+    pub fn new(inner: S, recorder: Recorder) -> Self {
+        Self {
+            inner,
+            recorder,
+            received: Vec::new(),
+            sent: Vec::new(),
+        }
+    }
+}
+
+/// Append data to the pending partial line
+/// and record any lines it completes.
+fn record_lines(
+    recorder: &Recorder,
+    direction: Direction,
+    pending: &mut Vec<u8>,
+    data: &[u8],
+) {
+    pending.extend(data);
+    while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+        let line = pending.drain(..=end).collect::<Vec<_>>();
+        recorder.record(direction, &line);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorded<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            record_lines(
+                &this.recorder,
+                Direction::Received,
+                &mut this.received,
+                &buf.filled()[start..],
+            );
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorded<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(len)) = poll {
+            record_lines(
+                &this.recorder,
+                Direction::Sent,
+                &mut this.sent,
+                &buf[..len],
+            );
+        }
+        poll
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mask_secrets;
+
+    #[test]
+    fn mask() {
+        assert_eq!(
+            mask_secrets("AUTH PLAIN AHVzZXIAcGFzcw=="),
+            "AUTH PLAIN ****"
+        );
+        assert_eq!(mask_secrets("AUTH LOGIN"), "AUTH LOGIN");
+        assert_eq!(mask_secrets("MAIL FROM:<a@b>"), "MAIL FROM:<a@b>");
+    }
+}
@@ -0,0 +1,252 @@
+use lettre::{address::Envelope, AsyncTransport, Transport};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream},
+    runtime::Handle,
+    sync::mpsc,
+};
+
+use crate::{
+    reply::{EnhancedCode, Reply},
+    server::Error,
+    smtp::{Auth, Protocol, Settings},
+    Email,
+};
+
+/// The buffer size of the in-memory connection to the server.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// A [`lettre`] transport that delivers emails
+/// straight into a [`Server`](crate::Server),
+/// without connecting to it over the network.
+///
+/// The client side of the SMTP exchange runs over an in-memory stream,
+/// so the server handles the email exactly as if it were sent
+/// by a regular client.
+/// Obtain a transport with [`Server::transport`](crate::Server::transport).
+///
+/// Both [`AsyncTransport`] and the blocking [`Transport`] are implemented.
+/// The blocking transport runs the exchange on the runtime
+/// the transport was created in, so it must not be used
+/// from within an async context of that runtime.
+///
+/// This type is only available with the `lettre` feature.
+#[derive(Clone)]
+pub struct ServerTransport {
+    settings: Settings,
+    hostname: String,
+    channel: mpsc::Sender<Result<Email, Error>>,
+    runtime: Handle,
+}
+
+/// An error while sending an email with a [`ServerTransport`].
+#[derive(thiserror::Error, Debug)]
+pub enum TransportError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("unexpected reply: {}", .0.to_string().trim_end())]
+    Reply(Reply),
+    #[error("invalid reply: {0:?}")]
+    InvalidReply(String),
+    #[error("server task stopped")]
+    Stopped,
+}
+
+impl ServerTransport {
+    pub(crate) fn new(
+        settings: Settings,
+        hostname: String,
+        channel: mpsc::Sender<Result<Email, Error>>,
+    ) -> Self {
+        Self {
+            settings,
+            hostname,
+            channel,
+            runtime: Handle::current(),
+        }
+    }
+
+    /// Run a complete SMTP exchange with the server
+    /// and return its final reply.
+    async fn deliver(
+        &self,
+        envelope: &Envelope,
+        email: &[u8],
+    ) -> Result<Reply, TransportError> {
+        let (client, server) = tokio::io::duplex(BUFFER_SIZE);
+        tokio::spawn(crate::server::task(
+            server,
+            self.hostname.clone(),
+            None,
+            self.settings.clone(),
+            self.channel.clone(),
+        ));
+        let mut client = BufReader::new(client);
+
+        expect_positive(read_reply(&mut client).await?)?;
+        let hello = match self.settings.protocol {
+            Protocol::Smtp => "EHLO localhost\r\n",
+            Protocol::Lmtp => "LHLO localhost\r\n",
+        };
+        command(&mut client, hello.as_bytes()).await?;
+        if let Auth::Login { username, password } = &self.settings.auth {
+            let password = crate::smtp::encode_password(username, password);
+            command(
+                &mut client,
+                format!("AUTH PLAIN {password}\r\n").as_bytes(),
+            )
+            .await?;
+        }
+        let from = envelope
+            .from()
+            .map(|address| address.to_string())
+            .unwrap_or_default();
+        command(&mut client, format!("MAIL FROM:<{from}>\r\n").as_bytes())
+            .await?;
+        for to in envelope.to() {
+            command(&mut client, format!("RCPT TO:<{to}>\r\n").as_bytes())
+                .await?;
+        }
+        command(&mut client, b"DATA\r\n").await?;
+
+        let reply = command(&mut client, &encode_data(email)).await?;
+        if self.settings.protocol == Protocol::Lmtp {
+            // NOTE: in LMTP the server sends one reply per recipient,
+            //       the first of which was already read
+            for _ in 1..envelope.to().len() {
+                expect_positive(read_reply(&mut client).await?)?;
+            }
+        }
+        command(&mut client, b"QUIT\r\n").await?;
+        Ok(reply)
+    }
+}
+
+impl std::fmt::Debug for ServerTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerTransport")
+            .field("hostname", &self.hostname)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait::async_trait]
+impl AsyncTransport for ServerTransport {
+    type Ok = Reply;
+    type Error = TransportError;
+
+    async fn send_raw(
+        &self,
+        envelope: &Envelope,
+        email: &[u8],
+    ) -> Result<Self::Ok, Self::Error> {
+        self.deliver(envelope, email).await
+    }
+}
+
+impl Transport for ServerTransport {
+    type Ok = Reply;
+    type Error = TransportError;
+
+    fn send_raw(
+        &self,
+        envelope: &Envelope,
+        email: &[u8],
+    ) -> Result<Self::Ok, Self::Error> {
+        let (result_tx, result_rx) = std::sync::mpsc::channel();
+        let transport = self.clone();
+        let envelope = envelope.clone();
+        let email = email.to_vec();
+        self.runtime.spawn(async move {
+            let result = transport.deliver(&envelope, &email).await;
+            let _ = result_tx.send(result);
+        });
+        result_rx.recv().map_err(|_| TransportError::Stopped)?
+    }
+}
+
+/// Dot-stuff the message content and append the end of data marker.
+fn encode_data(email: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(email.len() + 5);
+    for line in email.split_inclusive(|&byte| byte == b'\n') {
+        if line.starts_with(b".") {
+            data.push(b'.');
+        }
+        data.extend(line);
+    }
+    if !data.ends_with(b"\r\n") {
+        data.extend(b"\r\n");
+    }
+    data.extend(b".\r\n");
+    data
+}
+
+async fn command(
+    socket: &mut BufReader<DuplexStream>,
+    data: &[u8],
+) -> Result<Reply, TransportError> {
+    socket.get_mut().write_all(data).await?;
+    expect_positive(read_reply(socket).await?)
+}
+
+fn expect_positive(reply: Reply) -> Result<Reply, TransportError> {
+    if reply.is_positive() {
+        Ok(reply)
+    } else {
+        Err(TransportError::Reply(reply))
+    }
+}
+
+/// Read a possibly multiline reply from the server.
+async fn read_reply(
+    socket: &mut BufReader<DuplexStream>,
+) -> Result<Reply, TransportError> {
+    let mut code = None;
+    let mut enhanced = None;
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if socket.read_line(&mut line).await? == 0 {
+            return Err(TransportError::Stopped);
+        }
+        let invalid = || TransportError::InvalidReply(line.clone());
+        let content = line.trim_end_matches(['\r', '\n']);
+        let line_code = content.get(..3).ok_or_else(invalid)?;
+        let line_code = line_code.parse::<u16>().map_err(|_| invalid())?;
+        let rest = &content[3..];
+        let (last, rest) = match rest.chars().next() {
+            Some('-') => (false, &rest[1..]),
+            Some(' ') => (true, &rest[1..]),
+            None => (true, rest),
+            Some(_) => return Err(invalid()),
+        };
+        code.get_or_insert(line_code);
+        let (line_enhanced, text) = split_enhanced_code(rest);
+        enhanced = enhanced.or(line_enhanced);
+        lines.push(text.to_string());
+        if last {
+            let code = code.unwrap_or(line_code);
+            return Ok(Reply::new(code, enhanced, lines.join("\n")));
+        }
+    }
+}
+
+/// Split the enhanced status code from the start of a reply line, if any.
+fn split_enhanced_code(text: &str) -> (Option<EnhancedCode>, &str) {
+    let (first, rest) = text.split_once(' ').unwrap_or((text, ""));
+    let mut parts = first.split('.');
+    let code = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(class), Some(subject), Some(detail), None) => {
+            match (class.parse(), subject.parse(), detail.parse()) {
+                (Ok(class), Ok(subject), Ok(detail)) => {
+                    Some(EnhancedCode::new(class, subject, detail))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    match code {
+        Some(code) => (Some(code), rest),
+        None => (None, text),
+    }
+}